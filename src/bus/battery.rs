@@ -0,0 +1,68 @@
+use crate::bus::Bus;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// How often dirty battery-backed RAM is written back while the game runs
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Keeps battery-backed PRG-RAM in a .sav file next to the ROM
+pub struct BatterySave {
+    path: PathBuf,
+    last_flush: Instant,
+    dirty: bool,
+}
+
+impl BatterySave {
+    pub fn new(rom_path: &Path) -> Self {
+        BatterySave {
+            path: rom_path.with_extension("sav"),
+            last_flush: Instant::now(),
+            dirty: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Loads the .sav file into PRG-RAM, a missing file isn't an error
+    pub fn load(&self, bus: &mut Bus) -> io::Result<()> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                bus.load_prg_ram(&data);
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Writes PRG-RAM if it changed since the last flush
+    pub fn flush(&mut self, bus: &mut Bus) -> io::Result<()> {
+        self.dirty |= bus.take_prg_ram_dirty();
+
+        if self.dirty {
+            // Write to a temporary file first so that a crash can't leave a half-written save
+            let tmp = self.path.with_extension("sav.tmp");
+            fs::write(&tmp, bus.prg_ram())?;
+            fs::rename(&tmp, &self.path)?;
+
+            self.dirty = false;
+        }
+
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    // Called from the main loop, flushes once per FLUSH_INTERVAL
+    pub fn tick(&mut self, bus: &mut Bus) -> io::Result<()> {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush(bus)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+use std::cell::Cell;
+
+bitflags::bitflags! {
+    // Buttons in the order the controller shifts them out, A first
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct JoypadButtons: u8 {
+        const A      = 0b00000001;
+        const B      = 0b00000010;
+        const SELECT = 0b00000100;
+        const START  = 0b00001000;
+        const UP     = 0b00010000;
+        const DOWN   = 0b00100000;
+        const LEFT   = 0b01000000;
+        const RIGHT  = 0b10000000;
+    }
+}
+
+// Standard controller at $4016/$4017. Reads shift the buttons out one at a
+// time, so the index is a Cell to keep mem_read taking &self
+#[derive(Debug, Default)]
+pub struct Joypad {
+    strobe: bool,
+    index: Cell<u8>,
+    buttons: JoypadButtons,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad::default()
+    }
+
+    pub fn buttons(&self) -> JoypadButtons {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButtons) {
+        self.buttons = buttons;
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.index.set(0);
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let bit = self.peek();
+        let index = self.index.get();
+        if !self.strobe && index <= 7 {
+            self.index.set(index + 1);
+        }
+
+        bit
+    }
+
+    // The bit the next read returns, without shifting
+    pub fn peek(&self) -> u8 {
+        let index = self.index.get();
+        // After all 8 buttons an official controller keeps returning 1
+        if index > 7 {
+            return 1;
+        }
+
+        (self.buttons.bits() >> index) & 1
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.strobe);
+        state.u8(self.index.get());
+        state.u8(self.buttons.bits());
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.strobe = state.bool()?;
+        self.index.set(state.u8()?);
+        self.buttons = JoypadButtons::from_bits_retain(state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shift_out() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButtons::A | JoypadButtons::START | JoypadButtons::RIGHT);
+
+        joypad.write(1);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+
+        joypad.write(0);
+        assert_eq!(joypad.peek(), 1);
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+}
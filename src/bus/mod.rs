@@ -1,36 +1,293 @@
 use crate::cpu::mem::Mem;
 use crate::rom::Rom;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::symbols::{Location, Symbol, Symbols};
+use joypad::{Joypad, JoypadButtons};
+use watch::{Access, Watchpoints};
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+pub mod battery;
+pub mod joypad;
+pub mod watch;
+
+const CHR_RAM_SIZE: usize = 8192;
+const PRG_RAM_SIZE: usize = 8192;
+// Trainer is loaded at $7000, i.e. at this offset into PRG-RAM
+const TRAINER_OFFSET: usize = 0x1000;
+// How far back nearest_label looks for a label
+const NEAREST_LABEL_RANGE: u16 = 0x100;
+
+// What RAM holds after power on. Real SRAM comes up in a different state on
+// every console, so anything but Zeros helps find reads of uninitialised memory
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    Ones,
+    Random(u64),
+    // Four $00 bytes then four $FF bytes, what many consoles power up with
+    Pattern,
+}
+
+impl RamInit {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match self {
+            RamInit::Zeros => ram.fill(0),
+            RamInit::Ones => ram.fill(0xFF),
+            RamInit::Random(seed) => StdRng::seed_from_u64(*seed).fill_bytes(ram),
+            RamInit::Pattern => {
+                for (i, byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+        }
+    }
+}
 
 pub struct Bus {
     cpu_vram: [u8; 2048],
+    // Writable pattern memory for carts without CHR-ROM, empty otherwise
+    chr_ram: Vec<u8>,
+    // Cartridge work RAM at $6000-$7FFF, empty if the cart has none
+    prg_ram: Vec<u8>,
+    prg_ram_dirty: bool,
     rom: Rom,
+    joypads: [Joypad; 2],
+    ram_init: RamInit,
+    watchpoints: Watchpoints,
+    symbols: Symbols,
+    // CPU cycles since power on
+    cycles: usize,
 }
 
 impl Bus {
     pub fn new(rom: Rom) -> Self {
-        Bus {
+        let chr_ram = if rom.chr_rom.is_empty() {
+            vec![0; (rom.chr_ram_size + rom.chr_nvram_size).max(CHR_RAM_SIZE)]
+        } else {
+            vec![]
+        };
+
+        let mut prg_ram_size = rom.prg_ram_size + rom.prg_nvram_size;
+        if rom.trainer.is_some() {
+            prg_ram_size = prg_ram_size.max(PRG_RAM_SIZE);
+        }
+
+        let mut bus = Bus {
             cpu_vram: [0; 2048],
-            rom: rom,
+            chr_ram,
+            prg_ram: vec![0; prg_ram_size],
+            prg_ram_dirty: false,
+            rom,
+            joypads: [Joypad::new(), Joypad::new()],
+            ram_init: RamInit::default(),
+            watchpoints: Watchpoints::default(),
+            symbols: Symbols::default(),
+            cycles: 0,
+        };
+        bus.power_on();
+
+        bus
+    }
+
+    // Resets the memory a power cycle loses. Battery-backed PRG-RAM survives it
+    pub fn power_on(&mut self) {
+        self.ram_init.fill(&mut self.cpu_vram);
+        self.ram_init.fill(&mut self.chr_ram);
+        if !self.is_battery_backed() {
+            self.ram_init.fill(&mut self.prg_ram);
+        }
+
+        if let Some(trainer) = &self.rom.trainer {
+            self.prg_ram[TRAINER_OFFSET..TRAINER_OFFSET + trainer.len()].copy_from_slice(trainer);
         }
+
+        self.joypads = [Joypad::new(), Joypad::new()];
+        self.cycles = 0;
     }
 
-    pub fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr %= 0x4000;
+    pub fn ram_init(&self) -> RamInit {
+        self.ram_init
+    }
+
+    // Fills PRG-RAM from `ram_init` like power_on does without a battery
+    pub fn init_prg_ram(&mut self) {
+        self.ram_init.fill(&mut self.prg_ram);
+        self.prg_ram_dirty = false;
+    }
+
+    // Takes effect on the next power_on
+    pub fn set_ram_init(&mut self, ram_init: RamInit) {
+        self.ram_init = ram_init;
+    }
+
+    pub fn rom(&self) -> &Rom {
+        &self.rom
+    }
+
+    // Buttons held on controller `port`, 0 or 1
+    pub fn set_buttons(&mut self, port: usize, buttons: JoypadButtons) {
+        self.joypads[port].set_buttons(buttons);
+    }
+
+    pub fn buttons(&self, port: usize) -> JoypadButtons {
+        self.joypads[port].buttons()
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    // Name of the label at `addr`. Labels of a PRG bank only show where that
+    // bank is mapped
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.prg_offset(addr)
+            .and_then(|offset| self.symbols.at(Location::Prg(offset)))
+            .or_else(|| self.symbols.at(Location::Cpu(addr)))
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    // The closest label at or before `addr` and how far past it `addr` is,
+    // e.g. ("main", 3) for a return address in the middle of main
+    pub fn nearest_label(&self, addr: u16) -> Option<(&str, u16)> {
+        (0..NEAREST_LABEL_RANGE.min(addr.saturating_add(1)))
+            .find_map(|back| self.label(addr - back).map(|name| (name, back)))
+    }
+
+    // Where the CPU sees `symbol`, None if its bank isn't mapped
+    pub fn symbol_addr(&self, symbol: &Symbol) -> Option<u16> {
+        match symbol.location {
+            Location::Cpu(addr) => Some(addr),
+            Location::Prg(offset) => self.prg_addr(offset),
         }
+    }
 
-        self.rom.prg_rom[addr as usize]
+    // Offset into PRG-ROM of the byte at `addr`, None outside cartridge ROM
+    pub fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.rom.prg_rom.is_empty() {
+            return None;
+        }
+        Some((addr - 0x8000) as usize % self.rom.prg_rom.len())
     }
-}
 
-const RAM: u16 = 0x000;
-const RAM_MIRROR_END: u16 = 0x1FFF;
-const PPU_REGISTERS: u16 = 0x2000;
-const PPU_REGISTERS_END: u16 = 0x3FFF;
+    // CPU address a PRG-ROM offset is mapped at. A 16K ROM shows up twice,
+    // the upper copy is the one the vectors point into
+    pub fn prg_addr(&self, offset: usize) -> Option<u16> {
+        let len = self.rom.prg_rom.len();
+        if offset >= len.min(0x8000) {
+            return None;
+        }
+        Some((0x10000 - len.min(0x8000) + offset) as u16)
+    }
 
-impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    // The CPU doesn't tell opcode fetches from other reads, so execute
+    // watchpoints are checked by whoever steps it, before the instruction
+    pub fn watch_execute(&self, addr: u16) {
+        self.watchpoints.check(Access::EXECUTE, addr, self.peek(addr));
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn is_battery_backed(&self) -> bool {
+        self.rom.battery && !self.prg_ram.is_empty()
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    // Restores PRG-RAM contents, e.g. from a .sav file. Extra bytes are ignored
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+        self.prg_ram_dirty = false;
+    }
+
+    // Returns true if PRG-RAM was written since the last call
+    pub fn take_prg_ram_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.prg_ram_dirty)
+    }
+
+    pub fn has_chr_ram(&self) -> bool {
+        !self.chr_ram.is_empty()
+    }
+
+    // Pattern tables at PPU $0000-$1FFF
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        let addr = (addr & 0x1FFF) as usize;
+
+        if self.has_chr_ram() {
+            self.chr_ram[addr]
+        } else {
+            self.rom.chr_rom[addr % self.rom.chr_rom.len()]
+        }
+    }
+
+    pub fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.has_chr_ram() {
+            self.chr_ram[(addr & 0x1FFF) as usize] = data;
+        } else {
+            println!("Ignoring write to CHR-ROM at {:04X}", addr);
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.cpu_vram);
+        state.bytes(&self.chr_ram);
+        state.bytes(&self.prg_ram);
+        for joypad in &self.joypads {
+            joypad.save_state(state);
+        }
+        state.u64(self.cycles as u64);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.cpu_vram)?;
+        state.bytes_into(&mut self.chr_ram)?;
+        state.bytes_into(&mut self.prg_ram)?;
+        for joypad in &mut self.joypads {
+            joypad.load_state(state)?;
+        }
+        self.cycles = state.u64()? as usize;
+
+        // Battery RAM has to follow the loaded state on the next flush
+        self.prg_ram_dirty = !self.prg_ram.is_empty();
+        Ok(())
+    }
+
+    // What reading `addr` returns, without a read's side effects: controllers
+    // don't shift and watchpoints don't trigger. For debuggers and views
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read(addr, true)
+    }
+
+    // Opcode and operand bytes. A real read, but read watchpoints are for
+    // data, execute watchpoints already cover the instruction stream
+    pub fn fetch(&self, addr: u16) -> u8 {
+        self.read(addr, false)
+    }
+
+    fn read(&self, addr: u16, peek: bool) -> u8 {
         match addr {
             RAM..=RAM_MIRROR_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
@@ -38,21 +295,62 @@ impl Mem for Bus {
             }
 
             PPU_REGISTERS..=PPU_REGISTERS_END => {
-                let mirror_down_addr = addr & 0b00100000_00000111;
+                let _mirror_down_addr = addr & 0b00100000_00000111;
                 //todo!("PPU isn't implemented yet")
                 0
             }
 
+            JOYPAD_1 | JOYPAD_2 if peek => self.joypads[(addr - JOYPAD_1) as usize].peek(),
+
+            JOYPAD_1 => self.joypads[0].read(),
+
+            JOYPAD_2 => self.joypads[1].read(),
+
+            PRG_RAM..=PRG_RAM_END if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr - PRG_RAM) as usize % self.prg_ram.len()]
+            }
+
             0x8000..=0xFFFF => self.read_prg_rom(addr),
 
             _ => {
-                println!("Ignoring mem address at {}", addr);
+                if !peek {
+                    println!("Ignoring mem address at {}", addr);
+                }
                 0
             }
         }
     }
 
+    pub fn read_prg_rom(&self, mut addr: u16) -> u8 {
+        addr -= 0x8000;
+        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            addr %= 0x4000;
+        }
+
+        self.rom.prg_rom[addr as usize]
+    }
+}
+
+const RAM: u16 = 0x000;
+const RAM_MIRROR_END: u16 = 0x1FFF;
+const PPU_REGISTERS: u16 = 0x2000;
+const PPU_REGISTERS_END: u16 = 0x3FFF;
+const JOYPAD_1: u16 = 0x4016;
+const JOYPAD_2: u16 = 0x4017;
+pub const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+pub const CARTRIDGE_ROM: u16 = 0x8000;
+
+impl Mem for Bus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        let value = self.read(addr, false);
+        self.watchpoints.check(Access::READ, addr, value);
+        value
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.watchpoints.check(Access::WRITE, addr, data);
+
         match addr {
             RAM..=RAM_MIRROR_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
@@ -60,10 +358,22 @@ impl Mem for Bus {
             }
 
             PPU_REGISTERS..=PPU_REGISTERS_END => {
-                let mirror_down_addr = addr & 0b00100000_00000111;
+                let _mirror_down_addr = addr & 0b00100000_00000111;
                 //todo!("PPU isn't implemented yet")
             }
 
+            // One strobe line goes to both controllers
+            JOYPAD_1 => {
+                self.joypads[0].write(data);
+                self.joypads[1].write(data);
+            }
+
+            PRG_RAM..=PRG_RAM_END if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr - PRG_RAM) as usize % len] = data;
+                self.prg_ram_dirty = true;
+            }
+
             0x8000..=0xFFFF => {
                 panic!("Attemp to write to Cartrdige ROM space");
             }
@@ -74,3 +384,79 @@ impl Mem for Bus {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom(chr_banks: u8) -> Rom {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, chr_banks, 0b10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.resize(16 + 0x4000 + chr_banks as usize * 0x2000, 0x11);
+
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_chr_ram_is_writable() {
+        let mut bus = Bus::new(rom(0));
+        assert!(bus.has_chr_ram());
+
+        bus.write_chr(0x1234, 0x55);
+        assert_eq!(bus.read_chr(0x1234), 0x55);
+    }
+
+    #[test]
+    fn test_chr_rom_ignores_writes() {
+        let mut bus = Bus::new(rom(1));
+        assert!(!bus.has_chr_ram());
+
+        bus.write_chr(0x0010, 0x55);
+        assert_eq!(bus.read_chr(0x0010), 0x11);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(rom(1));
+        assert!(bus.is_battery_backed());
+        assert!(!bus.take_prg_ram_dirty());
+
+        bus.mem_write(0x6010, 0x42);
+        assert_eq!(bus.mem_read(0x6010), 0x42);
+        assert_eq!(bus.prg_ram()[0x10], 0x42);
+        assert!(bus.take_prg_ram_dirty());
+        assert!(!bus.take_prg_ram_dirty());
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut bus = Bus::new(rom(0));
+        bus.set_buttons(0, JoypadButtons::A);
+        bus.mem_write(JOYPAD_1, 1);
+        bus.mem_write(JOYPAD_1, 0);
+        bus.watchpoints_mut().add(JOYPAD_1, JOYPAD_1, Access::READ, None);
+
+        assert_eq!(bus.peek(JOYPAD_1), 1);
+        assert_eq!(bus.peek(JOYPAD_1), 1);
+        assert_eq!(bus.watchpoints().take_hit(), None);
+
+        assert_eq!(bus.mem_read(JOYPAD_1), 1);
+        assert_eq!(bus.peek(JOYPAD_1), 0);
+        assert!(bus.watchpoints().take_hit().is_some());
+    }
+
+    #[test]
+    fn test_ram_init() {
+        let mut bus = Bus::new(rom(0));
+        bus.set_ram_init(RamInit::Pattern);
+        bus.power_on();
+        let pattern: Vec<u8> = (0..9).map(|addr| bus.mem_read(addr)).collect();
+        assert_eq!(pattern, vec![0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0]);
+
+        bus.set_ram_init(RamInit::Random(42));
+        bus.power_on();
+        let first = crate::headless::dump_ram(&bus);
+        bus.power_on();
+        assert_eq!(crate::headless::dump_ram(&bus), first);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::debugger::Compare;
+
+use std::cell::Cell;
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Access: u8 {
+        const READ    = 0b001;
+        const WRITE   = 0b010;
+        const EXECUTE = 0b100;
+    }
+}
+
+impl Access {
+    // Any combination of r, w and x, e.g. "rw"
+    pub fn parse(text: &str) -> Result<Access, String> {
+        text.chars().try_fold(Access::empty(), |access, c| match c.to_ascii_lowercase() {
+            'r' => Ok(access | Access::READ),
+            'w' => Ok(access | Access::WRITE),
+            'x' => Ok(access | Access::EXECUTE),
+            _ => Err(format!("Unknown access {}, expected r, w or x", c)),
+        })
+    }
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (flag, c) in [(Access::READ, 'r'), (Access::WRITE, 'w'), (Access::EXECUTE, 'x')] {
+            if self.contains(flag) {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub id: usize,
+    // Inclusive range of CPU addresses
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+    // Value read, written or the opcode executed has to compare true
+    pub value: Option<(Compare, u8)>,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, addr: u16, value: u8) -> bool {
+        self.access.contains(access)
+            && (self.start..=self.end).contains(&addr)
+            && self.value.is_none_or(|(compare, expected)| compare.holds(value as u64, expected as u64))
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {} ${:04X}", self.id, self.access, self.start)?;
+        if self.end != self.start {
+            write!(f, "-${:04X}", self.end)?;
+        }
+        if let Some((compare, value)) = self.value {
+            write!(f, " if value {} ${:02X}", compare, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub access: Access,
+    pub addr: u16,
+    pub value: u8,
+}
+
+// Watchpoints are checked on every bus access. mem_read takes &self, so the
+// first hit is kept in a Cell until the debugger takes it
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    next_id: usize,
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, start: u16, end: u16, access: Access, value: Option<(Compare, u8)>) -> usize {
+        self.next_id += 1;
+        let (start, end) = (start.min(end), start.max(end));
+        self.list.push(Watchpoint { id: self.next_id, start, end, access, value });
+        self.next_id
+    }
+
+    // Returns false if there's no watchpoint `id`
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|watchpoint| watchpoint.id != id);
+        self.list.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    // Keeps the first hit until take_hit, an instruction can access several
+    // watched addresses and the debugger reports the first one
+    pub fn check(&self, access: Access, addr: u16, value: u8) {
+        if self.list.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+
+        if let Some(watchpoint) = self.list.iter().find(|watchpoint| watchpoint.matches(access, addr, value)) {
+            self.hit.set(Some(WatchHit { id: watchpoint.id, access, addr, value }));
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watch_ranges_and_values() {
+        let mut watchpoints = Watchpoints::default();
+        let id = watchpoints.add(0x0210, 0x0200, Access::WRITE, Some((Compare::Eq, 0x42)));
+
+        watchpoints.check(Access::READ, 0x0205, 0x42);
+        watchpoints.check(Access::WRITE, 0x0211, 0x42);
+        watchpoints.check(Access::WRITE, 0x0205, 0x41);
+        assert_eq!(watchpoints.take_hit(), None);
+
+        watchpoints.check(Access::WRITE, 0x0210, 0x42);
+        assert_eq!(watchpoints.take_hit(), Some(WatchHit { id, access: Access::WRITE, addr: 0x0210, value: 0x42 }));
+        assert_eq!(watchpoints.take_hit(), None);
+
+        // Later hits don't overwrite the one the debugger hasn't taken yet
+        watchpoints.check(Access::WRITE, 0x0200, 0x42);
+        watchpoints.check(Access::WRITE, 0x0201, 0x42);
+        assert_eq!(watchpoints.take_hit().map(|hit| hit.addr), Some(0x0200));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::bus::Bus;
+use crate::cpu::cpu::{AddressingMode, CPU, RESET_VECTOR};
+use crate::cpu::disasm::JMP_INDIRECT;
+use crate::cpu::opcodes;
+use crate::rom::Rom;
+
+// Instructions with a memory operand that write it without reading
+const STORES: [&str; 8] = ["STA", "STX", "STY", "*AAX", "*AXA", "*SXA", "*SYA", "*XAS"];
+
+bitflags::bitflags! {
+    // FCEUX's PRG byte flags. Bits 2-3 hold the 8K window of $8000-$FFFF
+    // the byte was last accessed through
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct PrgFlags: u8 {
+        const CODE          = 0b0000_0001;
+        const DATA          = 0b0000_0010;
+        const WINDOW        = 0b0000_1100;
+        // Target of JMP ($nnnn)
+        const INDIRECT_CODE = 0b0001_0000;
+        // Read through a pointer, e.g. LDA ($nn),Y
+        const INDIRECT_DATA = 0b0010_0000;
+        const PCM           = 0b0100_0000;
+    }
+}
+
+bitflags::bitflags! {
+    // There's no PPU yet, so these only come from logs made by FCEUX
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct ChrFlags: u8 {
+        const RENDERED = 0b01;
+        // Read by the CPU through PPUDATA
+        const READ     = 0b10;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CdlError {
+    Size { expected: usize, actual: usize },
+}
+
+impl std::fmt::Display for CdlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CdlError::Size { expected, actual } => write!(
+                f, "CDL file has {} bytes, the ROM needs {}", actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CdlError {}
+
+// PRG bytes with each kind of mark, for coverage reports. CHR isn't
+// counted since nothing marks it
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct CdlStats {
+    pub prg_size: usize,
+    pub code: usize,
+    pub data: usize,
+    // Neither code nor data, possibly unused content
+    pub prg_unused: usize,
+}
+
+impl std::fmt::Display for CdlStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |count: usize, size: usize| if size == 0 { 0.0 } else { count as f64 * 100.0 / size as f64 };
+
+        write!(
+            f, "PRG: {} code, {} data, {} unused of {} bytes ({:.1}% covered)",
+            self.code, self.data, self.prg_unused, self.prg_size,
+            percent(self.prg_size - self.prg_unused, self.prg_size)
+        )
+    }
+}
+
+// One flag byte per PRG-ROM and CHR-ROM byte, laid out like FCEUX's .cdl
+// files: all of PRG, then all of CHR. Marks only ever get added, so a log
+// loaded from an earlier session keeps growing. The CHR part stays as loaded,
+// zero for a new log, until there's a PPU to mark it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(rom: &Rom) -> Self {
+        CodeDataLog { prg: vec![0; rom.prg_rom.len()], chr: vec![0; rom.chr_rom.len()] }
+    }
+
+    // Reads a .cdl file made for `rom`
+    pub fn from_cdl(data: &[u8], rom: &Rom) -> Result<Self, CdlError> {
+        let mut log = CodeDataLog::new(rom);
+        let expected = log.prg.len() + log.chr.len();
+        if data.len() != expected {
+            return Err(CdlError::Size { expected, actual: data.len() });
+        }
+
+        let (prg, chr) = data.split_at(log.prg.len());
+        log.prg.copy_from_slice(prg);
+        log.chr.copy_from_slice(chr);
+        Ok(log)
+    }
+
+    pub fn to_cdl(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn prg(&self, offset: usize) -> PrgFlags {
+        PrgFlags::from_bits_retain(self.prg[offset])
+    }
+
+    pub fn chr(&self, offset: usize) -> ChrFlags {
+        ChrFlags::from_bits_retain(self.chr[offset])
+    }
+
+    // Marks the PRG-ROM byte the CPU sees at `addr`, nothing outside ROM
+    pub fn mark_prg(&mut self, bus: &Bus, addr: u16, flags: PrgFlags) {
+        let Some(flag) = bus.prg_offset(addr).and_then(|offset| self.prg.get_mut(offset)) else { return };
+        let window = ((addr >> 13) & 0b11) as u8;
+        *flag = (*flag & !PrgFlags::WINDOW.bits()) | flags.bits() | window << 2;
+    }
+
+    // The CPU reads the reset vector on power on and reset
+    pub fn log_reset(&mut self, bus: &Bus) {
+        self.mark_prg(bus, RESET_VECTOR, PrgFlags::DATA);
+        self.mark_prg(bus, RESET_VECTOR + 1, PrgFlags::DATA);
+    }
+
+    // Call before the instruction at PC runs. Marks its bytes as code and
+    // whatever ROM it reads, or jumps to through a pointer
+    pub fn log_instruction(&mut self, cpu: &CPU) {
+        let pc = cpu.program_counter;
+        let bus = &cpu.bus;
+        let opcode = opcodes::OPCODES_MAP[&bus.peek(pc)];
+
+        for i in 0..opcode.len as u16 {
+            self.mark_prg(bus, pc.wrapping_add(i), PrgFlags::CODE);
+        }
+
+        match opcode.mode {
+            AddressingMode::Immediate => {}
+            AddressingMode::NoneAddressing if opcode.code == JMP_INDIRECT => {
+                // The pointer's high byte comes from the same page
+                let pointer = u16::from_le_bytes([bus.peek(pc.wrapping_add(1)), bus.peek(pc.wrapping_add(2))]);
+                let high = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+                self.mark_prg(bus, pointer, PrgFlags::DATA);
+                self.mark_prg(bus, high, PrgFlags::DATA);
+
+                let target = u16::from_le_bytes([bus.peek(pointer), bus.peek(high)]);
+                self.mark_prg(bus, target, PrgFlags::INDIRECT_CODE);
+            }
+            AddressingMode::NoneAddressing => {}
+            _ if STORES.contains(&opcode.mnemonic) => {}
+            ref mode => {
+                let addr = cpu.peek_absolute_address(mode, pc.wrapping_add(1));
+                let indirect = matches!(mode, AddressingMode::Indirect_X | AddressingMode::Indirect_Y);
+                let flags = if indirect { PrgFlags::DATA | PrgFlags::INDIRECT_DATA } else { PrgFlags::DATA };
+                self.mark_prg(bus, addr, flags);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CdlStats {
+        let prg_count = |flags: PrgFlags| self.prg.iter().filter(|flag| **flag & flags.bits() != 0).count();
+
+        CdlStats {
+            prg_size: self.prg.len(),
+            code: prg_count(PrgFlags::CODE),
+            data: prg_count(PrgFlags::DATA),
+            prg_unused: self.prg.len() - prg_count(PrgFlags::CODE | PrgFlags::DATA),
+        }
+    }
+
+    // PRG offsets where a run of code starts, each one an instruction's
+    // first byte. Handy entry points for the disassembler
+    pub fn code_starts(&self) -> Vec<usize> {
+        (0..self.prg.len())
+            .filter(|offset| self.prg(*offset).contains(PrgFlags::CODE))
+            .filter(|offset| *offset == 0 || !self.prg(offset - 1).contains(PrgFlags::CODE))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::mem::Mem;
+    use crate::nes::test_util::{load_program, nestest};
+
+    #[test]
+    fn test_log_code_and_data() {
+        let mut nes = nestest();
+        nes.set_cdl(Some(CodeDataLog::new(nes.rom())));
+        nes.power_on();
+
+        // 16K of PRG shows at $8000 and $C000, so $C010 is offset $0010
+        load_program(&mut nes, "LDA $C010\nLDY #0\nLDA ($10),Y\nSTA $0200\nBRK");
+        nes.cpu_mut().mem_write(0x10, 0x30);
+        nes.cpu_mut().mem_write(0x11, 0xC0);
+        while nes.step() {}
+
+        // The test ROM itself starts at $C000 with JMP $C5F5
+        nes.reset();
+        nes.cpu_mut().program_counter = 0xC000;
+        nes.step();
+
+        let cdl = nes.cdl().unwrap();
+        assert_eq!(cdl.prg(0x0010), PrgFlags::DATA | PrgFlags::from_bits_retain(0b10 << 2));
+        assert_eq!(cdl.prg(0x0030), PrgFlags::DATA | PrgFlags::INDIRECT_DATA | PrgFlags::from_bits_retain(0b10 << 2));
+        assert!((0..3).all(|offset| cdl.prg(offset).contains(PrgFlags::CODE)));
+        assert!(cdl.prg(0x3FFC).contains(PrgFlags::DATA));
+        assert_eq!(cdl.code_starts(), vec![0]);
+
+        let stats = cdl.stats();
+        assert_eq!((stats.code, stats.data, stats.prg_unused), (3, 4, 0x4000 - 7));
+
+        let reloaded = CodeDataLog::from_cdl(&cdl.to_cdl(), nes.rom()).unwrap();
+        assert_eq!(&reloaded, cdl);
+        assert_eq!(CodeDataLog::from_cdl(&[0; 16], nes.rom()), Err(CdlError::Size { expected: 0x6000, actual: 16 }));
+    }
+}
@@ -0,0 +1,292 @@
+use famemu::headless::{parse_number, RamCondition};
+use famemu::movie::DEFAULT_HASH_INTERVAL;
+use famemu::tracer::TraceFormat;
+use famemu::Timing;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(name = "famemu", version, about = "Famicom emulator")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a ROM
+    Run(RunArgs),
+
+    /// Print the parsed ROM header
+    Info {
+        #[command(flatten)]
+        rom: RomArgs,
+    },
+
+    /// Run a ROM headless and write the instruction trace to a file
+    Trace {
+        #[command(flatten)]
+        rom: RomArgs,
+
+        /// File to write the trace to
+        #[arg(long)]
+        out: PathBuf,
+
+        #[command(flatten)]
+        trace: TraceArgs,
+
+        #[command(flatten)]
+        emulation: EmulationArgs,
+    },
+
+    /// Run a ROM headless under an interactive debugger, paused at the first instruction
+    Debug {
+        #[command(flatten)]
+        rom: RomArgs,
+
+        #[command(flatten)]
+        emulation: EmulationArgs,
+    },
+
+    /// Run a ROM headless and wait for GDB to connect over TCP
+    Gdb {
+        #[command(flatten)]
+        rom: RomArgs,
+
+        /// Port to listen on, on 127.0.0.1 only
+        #[arg(long, default_value_t = 6502)]
+        port: u16,
+
+        #[command(flatten)]
+        emulation: EmulationArgs,
+    },
+
+    /// Disassemble PRG-ROM to ca65 source
+    Disasm {
+        #[command(flatten)]
+        rom: RomArgs,
+
+        /// 16K PRG bank to disassemble, by default all of PRG-ROM if it fits in 32K
+        #[arg(long)]
+        bank: Option<usize>,
+
+        /// Address the code is mapped at, by default $C000 for the last bank and $8000 for others
+        #[arg(long, value_parser = parse_addr)]
+        base: Option<u16>,
+
+        /// Extra code entry points besides the vectors, e.g. `--entry-point '$8123'`
+        #[arg(long, value_parser = parse_addr)]
+        entry_point: Vec<u16>,
+
+        /// FCEUX code/data log, code it saw running is disassembled too
+        #[arg(long)]
+        cdl: Option<PathBuf>,
+
+        /// File to write the source to, stdout by default
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Run a test ROM headless and report its result ($6000 status protocol)
+    Test {
+        #[command(flatten)]
+        rom: RomArgs,
+
+        #[command(flatten)]
+        emulation: EmulationArgs,
+    },
+}
+
+fn parse_addr(text: &str) -> Result<u16, String> {
+    let addr = parse_number(text)?;
+    u16::try_from(addr).map_err(|_| format!("Address {} is out of range", text))
+}
+
+// `$8000-$80FF`
+fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    let (start, end) = text.split_once('-').ok_or(format!("Expected START-END, got {}", text))?;
+    Ok((parse_addr(start)?, parse_addr(end)?))
+}
+
+#[derive(Args)]
+pub struct TraceArgs {
+    /// Trace line format
+    #[arg(long, value_enum, default_value_t = TraceFormatArg::Nestest)]
+    pub trace_format: TraceFormatArg,
+
+    /// Only trace instructions in this PC range, e.g. `$8000-$80FF`
+    #[arg(long, value_parser = parse_range)]
+    pub trace_pc: Option<(u16, u16)>,
+
+    /// Only trace code running from this 16K PRG bank
+    #[arg(long)]
+    pub trace_bank: Option<usize>,
+
+    /// Start tracing once a debugger condition holds, e.g. `pc == $8123` or `frame >= 100 && [$00F0] == 1`
+    #[arg(long)]
+    pub trace_when: Option<String>,
+}
+
+#[derive(Args)]
+pub struct RomArgs {
+    /// ROM image: .nes, .nes.gz or a .zip archive
+    pub rom: PathBuf,
+
+    /// IPS, BPS or UPS patch, by default one next to the ROM is used
+    #[arg(long)]
+    pub patch: Option<PathBuf>,
+
+    /// ROM to pick inside a zip archive, by default the first one
+    #[arg(long)]
+    pub entry: Option<String>,
+
+    /// Symbol file: ca65 .dbg, FCEUX .nl or Mesen .mlb. By default the ones
+    /// next to the ROM are loaded, e.g. game.nes.ram.nl and game.nes.0.nl
+    #[arg(long)]
+    pub symbols: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct EmulationArgs {
+    /// Override the region from the ROM header
+    #[arg(long, value_enum)]
+    pub region: Option<Region>,
+
+    /// Stop after this many frames
+    #[arg(long)]
+    pub frames: Option<usize>,
+
+    /// What RAM holds at power on
+    #[arg(long, value_enum, default_value_t = RamInitArg::Zeros)]
+    pub ram_init: RamInitArg,
+
+    /// Seed for `--ram-init random`, by default a new one is picked and printed
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Feed the snake demo random numbers at $FE and WASD keys at $FF
+    #[arg(long)]
+    pub snake_io: bool,
+
+    /// FCEUX code/data log (.cdl) to add this session's marks to, created if missing
+    #[arg(long)]
+    pub cdl: Option<PathBuf>,
+
+    /// Profile cycles per subroutine, writes folded stacks for flame graphs here
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub rom: RomArgs,
+
+    #[command(flatten)]
+    pub emulation: EmulationArgs,
+
+    /// Window scale factor
+    #[arg(long, default_value_t = 10)]
+    pub scale: u32,
+
+    /// Write a trace line for every instruction to a file, F9 toggles it in the window
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+
+    /// Start with tracing off
+    #[arg(long, requires = "trace")]
+    pub trace_paused: bool,
+
+    #[command(flatten)]
+    pub trace_args: TraceArgs,
+
+    /// Run without a window
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Load a quick save slot (0-9) before running
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..10))]
+    pub load_state: Option<u8>,
+
+    /// Record controller input to an FM2 movie, from power on or from --load-state
+    #[arg(long, conflicts_with = "play")]
+    pub record: Option<PathBuf>,
+
+    /// Play an FM2 movie
+    #[arg(long)]
+    pub play: Option<PathBuf>,
+
+    /// Frames between the RAM hashes a recording stores to detect desyncs
+    #[arg(long, default_value_t = DEFAULT_HASH_INTERVAL)]
+    pub hash_interval: usize,
+
+    /// Frames between rewind snapshots
+    #[arg(long, default_value_t = 1)]
+    pub rewind_interval: usize,
+
+    /// Memory for rewind snapshots, in MiB
+    #[arg(long, default_value_t = 64)]
+    pub rewind_budget: usize,
+
+    /// Headless: stop once a RAM byte matches, e.g. `$00F0==$01`
+    #[arg(long, requires = "headless")]
+    pub until: Option<RamCondition>,
+
+    /// Headless: write the final frame as PNG
+    #[arg(long, requires = "headless")]
+    pub png: Option<PathBuf>,
+
+    /// Headless: write the 2K of CPU RAM at the end of the run
+    #[arg(long, requires = "headless")]
+    pub ram_dump: Option<PathBuf>,
+
+    /// Headless: write the audio output as WAV
+    #[arg(long, requires = "headless")]
+    pub wav: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum RamInitArg {
+    Zeros,
+    /// All $FF
+    Ones,
+    Random,
+    /// Four $00 bytes then four $FF bytes
+    Pattern,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum TraceFormatArg {
+    /// nestest.log columns
+    Nestest,
+    Mesen,
+    Fceux,
+}
+
+impl From<TraceFormatArg> for TraceFormat {
+    fn from(format: TraceFormatArg) -> Self {
+        match format {
+            TraceFormatArg::Nestest => TraceFormat::Nestest,
+            TraceFormatArg::Mesen => TraceFormat::Mesen,
+            TraceFormatArg::Fceux => TraceFormat::Fceux,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl From<Region> for Timing {
+    fn from(region: Region) -> Self {
+        match region {
+            Region::Ntsc => Timing::Ntsc,
+            Region::Pal => Timing::Pal,
+            Region::Dendy => Timing::Dendy,
+        }
+    }
+}
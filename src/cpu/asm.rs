@@ -0,0 +1,685 @@
+use crate::cpu::cpu::AddressingMode;
+use crate::cpu::disasm::JMP_INDIRECT;
+use crate::cpu::opcodes::{OpCode, CPU_OPS_CODES};
+
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq)]
+pub enum AsmError {
+    Syntax { line: usize, message: String },
+    // Mnemonic doesn't exist or doesn't take this addressing mode
+    UnknownInstruction { line: usize, text: String },
+    UndefinedSymbol { line: usize, name: String },
+    DuplicateSymbol { line: usize, name: String },
+    BranchOutOfRange { line: usize, target: i64 },
+    // Value doesn't fit in the byte or word it's used as
+    OutOfRange { line: usize, value: i64 },
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmError::Syntax { line, message } => write!(f, "Line {}: {}", line, message),
+            AsmError::UnknownInstruction { line, text } => write!(f, "Line {}: unknown instruction {}", line, text),
+            AsmError::UndefinedSymbol { line, name } => write!(f, "Line {}: {} isn't defined", line, name),
+            AsmError::DuplicateSymbol { line, name } => write!(f, "Line {}: {} is already defined", line, name),
+            AsmError::BranchOutOfRange { line, target } if (0..=0xFFFF).contains(target) => {
+                write!(f, "Line {}: branch to ${:04X} is too far", line, target)
+            }
+            AsmError::BranchOutOfRange { line, target } => write!(f, "Line {}: branch to {} is too far", line, target),
+            AsmError::OutOfRange { line, value } => write!(f, "Line {}: {} is out of range", line, value),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    // `*`, the address of the current line
+    Pc,
+    // `-`, `<` low byte, `>` high byte
+    Unary(char, Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+}
+
+// Binary operators from the loosest binding to the tightest
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.pos = self.text.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        // `<` and `>` mustn't match the start of `<<` and `>>`
+        let shift = token.len() == 1 && self.rest().starts_with(&token.repeat(2)) && "<>".contains(token);
+        if self.rest().starts_with(token) && !shift {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, keep: F) -> &'a str {
+        let start = self.pos;
+        let len = self.rest().find(|c: char| !keep(c)).unwrap_or(self.rest().len());
+        self.pos += len;
+        &self.text[start..self.pos]
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            for op in PRECEDENCE[level] {
+                if self.eat(op) {
+                    lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.binary(level + 1)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        for op in ['-', '<', '>'] {
+            if self.eat(&op.to_string()) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+
+        if self.eat("(") {
+            let expr = self.expr()?;
+            return if self.eat(")") { Ok(expr) } else { Err("Missing )".to_string()) };
+        }
+        if self.eat("*") {
+            return Ok(Expr::Pc);
+        }
+
+        self.skip_spaces();
+        let number = |digits: &str, radix| i64::from_str_radix(digits, radix).map(Expr::Number);
+        let parsed = if self.eat("$") {
+            number(self.take_while(|c| c.is_ascii_hexdigit()), 16)
+        } else if self.eat("%") {
+            number(self.take_while(|c| c == '0' || c == '1'), 2)
+        } else if self.eat("'") {
+            let c = self.rest().chars().next().ok_or("Missing character")?;
+            self.pos += c.len_utf8();
+            self.eat("'");
+            Ok(Expr::Number(c as i64))
+        } else if self.rest().starts_with(|c: char| c.is_ascii_digit()) {
+            number(self.take_while(|c| c.is_ascii_digit()), 10)
+        } else {
+            let name = self.take_while(is_symbol_char);
+            if name.is_empty() {
+                return Err(format!("Expected an expression at `{}`", self.rest()));
+            }
+            Ok(Expr::Symbol(name.to_string()))
+        };
+
+        parsed.map_err(|_| format!("Invalid number at `{}`", self.rest()))
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@'
+}
+
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let expr = parser.expr()?;
+    parser.skip_spaces();
+
+    if parser.rest().is_empty() {
+        Ok(expr)
+    } else {
+        Err(format!("Unexpected `{}`", parser.rest()))
+    }
+}
+
+// Forces the operand size like ca65's `a:` and `z:`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Width {
+    Auto,
+    ZeroPage,
+    Absolute,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Implied,
+    Accumulator,
+    Immediate(Expr),
+    // `expr`, `expr,X` or `expr,Y`
+    Direct { expr: Expr, index: Option<char>, width: Width },
+    IndirectX(Expr),
+    IndirectY(Expr),
+    Indirect(Expr),
+}
+
+// Splits at the last comma that isn't inside parentheses or quotes
+fn split_index(text: &str) -> Option<(&str, char)> {
+    let (rest, index) = text.rsplit_once(',')?;
+    let index = index.trim().to_ascii_uppercase();
+    match index.as_str() {
+        "X" | "Y" if rest.matches('(').count() == rest.matches(')').count() => Some((rest, index.chars().next().unwrap())),
+        _ => None,
+    }
+}
+
+// Index of the parenthesis that closes the one at the start of `text`
+fn closing_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 1 => return Some(i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_operand(text: &str, mnemonic: &str) -> Result<Operand, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(Operand::Implied);
+    }
+    if text.eq_ignore_ascii_case("a") {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(value)?));
+    }
+
+    if text.starts_with('(') {
+        let close = closing_paren(text).ok_or("Missing )")?;
+        let (inner, after) = (&text[1..close], text[close + 1..].trim());
+
+        match split_index(inner) {
+            Some((expr, 'X')) if after.is_empty() => return Ok(Operand::IndirectX(parse_expr(expr)?)),
+            _ => {}
+        }
+        if after.to_ascii_uppercase().replace(' ', "") == ",Y" {
+            return Ok(Operand::IndirectY(parse_expr(inner)?));
+        }
+        if after.is_empty() && mnemonic == "JMP" {
+            return Ok(Operand::Indirect(parse_expr(inner)?));
+        }
+    }
+
+    let (text, width) = match text.get(..2).map(str::to_ascii_lowercase).as_deref() {
+        Some("a:") => (&text[2..], Width::Absolute),
+        Some("z:") => (&text[2..], Width::ZeroPage),
+        _ => (text, Width::Auto),
+    };
+
+    match split_index(text) {
+        Some((expr, index)) => Ok(Operand::Direct { expr: parse_expr(expr)?, index: Some(index), width }),
+        None => Ok(Operand::Direct { expr: parse_expr(text)?, index: None, width }),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Data {
+    Expr(Expr),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Label(String),
+    Assign(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Data>),
+    Words(Vec<Expr>),
+    // `.res count[, fill]`
+    Reserve(Expr, Option<Expr>),
+    Instruction { mnemonic: String, operand: Operand },
+}
+
+// Splits on commas outside of quotes
+fn split_list(text: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let (mut start, mut quoted) = (0, false);
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            // A character literal like ';'
+            '\'' if !quoted => {
+                chars.next();
+            }
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_directive(directive: &str, args: &str) -> Result<Option<Statement>, String> {
+    let exprs = || split_list(args).into_iter().map(parse_expr).collect::<Result<Vec<_>, _>>();
+
+    let statement = match directive.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(parse_expr(args)?),
+        ".byte" | ".byt" | ".db" => {
+            let data = split_list(args).into_iter().map(|item| {
+                let item = item.trim();
+                match item.strip_prefix('"').and_then(|item| item.strip_suffix('"')) {
+                    Some(text) => Ok(Data::Text(text.to_string())),
+                    None => parse_expr(item).map(Data::Expr),
+                }
+            });
+            Statement::Bytes(data.collect::<Result<_, _>>()?)
+        }
+        ".word" | ".addr" | ".dw" => Statement::Words(exprs()?),
+        ".res" | ".ds" => {
+            let mut exprs = exprs()?.into_iter();
+            Statement::Reserve(exprs.next().ok_or("Missing size")?, exprs.next())
+        }
+        // Only the 6502 is supported, segments don't mean anything here
+        ".setcpu" | ".segment" | ".p02" => return Ok(None),
+        _ => return Err(format!("Unknown directive {}", directive)),
+    };
+
+    Ok(Some(statement))
+}
+
+fn parse_line(line: &str) -> Result<Vec<Statement>, String> {
+    let mut statements = vec![];
+    let mut line = strip_comment(line).trim();
+
+    // Any number of `label:` in front
+    loop {
+        let name_len = line.find(|c: char| !is_symbol_char(c)).unwrap_or(line.len());
+        match line[name_len..].strip_prefix(':') {
+            Some(rest) if name_len > 0 && !rest.starts_with('=') => {
+                statements.push(Statement::Label(line[..name_len].to_string()));
+                line = rest.trim_start();
+            }
+            _ => break,
+        }
+    }
+
+    if line.is_empty() {
+        return Ok(statements);
+    }
+
+    let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    if let Some(value) = rest.strip_prefix(":=").or_else(|| rest.strip_prefix('=')) {
+        statements.push(Statement::Assign(word.to_string(), parse_expr(value)?));
+    } else if let Some((name, value)) = line.split_once('=').filter(|(name, _)| name.chars().all(is_symbol_char)) {
+        statements.push(Statement::Assign(name.to_string(), parse_expr(value)?));
+    } else if word.starts_with('.') {
+        statements.extend(parse_directive(word, rest)?);
+    } else {
+        let mnemonic = word.to_ascii_uppercase();
+        let operand = parse_operand(rest, &mnemonic)?;
+        statements.push(Statement::Instruction { mnemonic, operand });
+    }
+
+    Ok(statements)
+}
+
+// Official opcodes win, so NOP is $EA rather than one of the unofficial NOPs.
+// Unofficial ones can be written with or without their `*`
+fn find_opcode<F: Fn(&OpCode) -> bool>(mnemonic: &str, matches: F) -> Option<&'static OpCode> {
+    let unofficial = format!("*{}", mnemonic.trim_start_matches('*'));
+    CPU_OPS_CODES.iter().find(|op| op.mnemonic == mnemonic && matches(op))
+        .or_else(|| CPU_OPS_CODES.iter().find(|op| op.mnemonic == unofficial && matches(op)))
+}
+
+fn is_mode(opcode: &OpCode, mode: &AddressingMode) -> bool {
+    std::mem::discriminant(&opcode.mode) == std::mem::discriminant(mode)
+}
+
+fn is_branch(opcode: &OpCode) -> bool {
+    opcode.len == 2 && is_mode(opcode, &AddressingMode::NoneAddressing)
+}
+
+// Two pass assembler for the instructions in CPU_OPS_CODES. Supports labels,
+// `name = expr`, expressions with `* / % + - << >> & ^ |`, `<` and `>` for the
+// low and high byte, `*` for the current address, and the directives .org,
+// .byte, .word and .res. Forward references are assembled as absolute, like
+// ca65 does
+pub struct Assembler {
+    origin: u16,
+    symbols: HashMap<String, i64>,
+}
+
+impl Assembler {
+    pub fn new(origin: u16) -> Self {
+        Assembler { origin, symbols: HashMap::new() }
+    }
+
+    // Makes `name` usable in the source, e.g. symbols of the running game
+    pub fn define(&mut self, name: &str, value: u16) {
+        self.symbols.insert(name.to_string(), value as i64);
+    }
+
+    // Labels and constants after `assemble`
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).map(|value| *value as u16)
+    }
+
+    // Returns the bytes from the origin, or from the first .org if it comes
+    // before any code. Gaps left by .org are filled with zeros
+    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, AsmError> {
+        let mut lines = vec![];
+        for (i, line) in source.lines().enumerate() {
+            for statement in parse_line(line).map_err(|message| AsmError::Syntax { line: i + 1, message })? {
+                lines.push((i + 1, statement));
+            }
+        }
+
+        let predefined = self.symbols.clone();
+        // Pass 1 picks the opcodes, pass 2 encodes them with every symbol known
+        let result = self.pass(&lines, None).and_then(|first| self.pass(&lines, Some(&first.opcodes)));
+
+        if result.is_err() {
+            self.symbols = predefined;
+        }
+        result.map(|pass| pass.bytes)
+    }
+
+    fn pass(&mut self, lines: &[(usize, Statement)], opcodes: Option<&Vec<&'static OpCode>>) -> Result<Pass, AsmError> {
+        let final_pass = opcodes.is_some();
+        let mut pass = Pass { pc: self.origin as usize, bytes: vec![], opcodes: vec![] };
+        let mut defined = vec![];
+
+        for (line, statement) in lines {
+            let line = *line;
+            let eval = |expr: &Expr, pc: usize| -> Result<Option<i64>, AsmError> {
+                match self.eval(expr, pc as i64) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(_) if !final_pass => Ok(None),
+                    Err(name) => Err(AsmError::UndefinedSymbol { line, name }),
+                }
+            };
+
+            match statement {
+                Statement::Label(name) | Statement::Assign(name, _) => {
+                    let value = match statement {
+                        Statement::Assign(_, expr) => eval(expr, pass.pc)?,
+                        _ => Some(pass.pc as i64),
+                    };
+
+                    if !final_pass && defined.contains(name) {
+                        return Err(AsmError::DuplicateSymbol { line, name: name.clone() });
+                    }
+                    defined.push(name.clone());
+                    if let Some(value) = value {
+                        self.symbols.insert(name.clone(), value);
+                    }
+                }
+                Statement::Org(expr) => {
+                    let org = eval(expr, pass.pc)?.ok_or(AsmError::Syntax { line, message: ".org needs a known address".to_string() })?;
+                    let org = word(org, line)? as usize;
+                    if pass.bytes.is_empty() {
+                        pass.pc = org;
+                    } else if org < pass.pc {
+                        return Err(AsmError::Syntax { line, message: ".org can't go backwards".to_string() });
+                    } else {
+                        pass.emit(&vec![0; org - pass.pc]);
+                    }
+                }
+                Statement::Bytes(data) => {
+                    for item in data {
+                        match item {
+                            Data::Text(text) => pass.emit(text.as_bytes()),
+                            Data::Expr(expr) => {
+                                let value = eval(expr, pass.pc)?.map(|value| byte(value, line)).transpose()?;
+                                pass.emit(&[value.unwrap_or(0)]);
+                            }
+                        }
+                    }
+                }
+                Statement::Words(exprs) => {
+                    for expr in exprs {
+                        let value = eval(expr, pass.pc)?.map(|value| word(value, line)).transpose()?;
+                        pass.emit(&value.unwrap_or(0).to_le_bytes());
+                    }
+                }
+                Statement::Reserve(count, fill) => {
+                    let count = eval(count, pass.pc)?.ok_or(AsmError::Syntax { line, message: ".res needs a known size".to_string() })?;
+                    let fill = match fill {
+                        Some(fill) => eval(fill, pass.pc)?.map(|value| byte(value, line)).transpose()?.unwrap_or(0),
+                        None => 0,
+                    };
+                    pass.emit(&vec![fill; word(count, line)? as usize]);
+                }
+                Statement::Instruction { mnemonic, operand } => {
+                    let opcode = match opcodes {
+                        Some(opcodes) => opcodes[pass.opcodes.len()],
+                        None => self.pick_opcode(mnemonic, operand, pass.pc, line)?,
+                    };
+                    pass.opcodes.push(opcode);
+
+                    let value = match operand {
+                        Operand::Implied | Operand::Accumulator => None,
+                        Operand::Immediate(expr) | Operand::Direct { expr, .. } | Operand::IndirectX(expr)
+                        | Operand::IndirectY(expr) | Operand::Indirect(expr) => Some(eval(expr, pass.pc)?.unwrap_or(0)),
+                    };
+
+                    let mut bytes = vec![opcode.code];
+                    match (opcode.len, value) {
+                        (2, Some(target)) if is_branch(opcode) && final_pass => {
+                            let offset = target - (pass.pc as i64 + 2);
+                            if !(-128..=127).contains(&offset) {
+                                return Err(AsmError::BranchOutOfRange { line, target });
+                            }
+                            bytes.push(offset as u8);
+                        }
+                        (2, Some(value)) => bytes.push(if final_pass { byte(value, line)? } else { 0 }),
+                        (3, Some(value)) => bytes.extend_from_slice(&word(value, line)?.to_le_bytes()),
+                        _ => {}
+                    }
+                    pass.emit(&bytes);
+                }
+            }
+
+            if pass.pc > 0x10000 {
+                return Err(AsmError::Syntax { line, message: "Code runs past $FFFF".to_string() });
+            }
+        }
+
+        Ok(pass)
+    }
+
+    // Chosen in the first pass, so a value that's still unknown makes the
+    // operand absolute
+    fn pick_opcode(&self, mnemonic: &str, operand: &Operand, pc: usize, line: usize) -> Result<&'static OpCode, AsmError> {
+        let found = match operand {
+            Operand::Implied | Operand::Accumulator => find_opcode(mnemonic, |op| op.len == 1),
+            Operand::Immediate(_) => find_opcode(mnemonic, |op| is_mode(op, &AddressingMode::Immediate)),
+            Operand::IndirectX(_) => find_opcode(mnemonic, |op| is_mode(op, &AddressingMode::Indirect_X)),
+            Operand::IndirectY(_) => find_opcode(mnemonic, |op| is_mode(op, &AddressingMode::Indirect_Y)),
+            Operand::Indirect(_) => find_opcode(mnemonic, |op| op.code == JMP_INDIRECT),
+            Operand::Direct { expr, index, width } => {
+                let (zero_page, absolute) = match index {
+                    None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                    Some('X') => (AddressingMode::ZeroPage_X, AddressingMode::Absolute_X),
+                    _ => (AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y),
+                };
+                let fits = self.eval(expr, pc as i64).is_ok_and(|value| (0..0x100).contains(&value));
+                let use_zero_page = match width {
+                    Width::ZeroPage => true,
+                    Width::Absolute => false,
+                    Width::Auto => fits,
+                };
+
+                // Branches, JMP and JSR only have the one mode
+                let flow = if index.is_none() && *width == Width::Auto {
+                    find_opcode(mnemonic, |op| {
+                        is_mode(op, &AddressingMode::NoneAddressing) && op.len > 1 && op.code != JMP_INDIRECT
+                    })
+                } else {
+                    None
+                };
+
+                flow.or_else(|| use_zero_page.then(|| find_opcode(mnemonic, |op| is_mode(op, &zero_page))).flatten())
+                    .or_else(|| (*width != Width::ZeroPage).then(|| find_opcode(mnemonic, |op| is_mode(op, &absolute))).flatten())
+            }
+        };
+
+        found.ok_or_else(|| AsmError::UnknownInstruction { line, text: mnemonic.to_string() })
+    }
+
+    // Err holds the name of an undefined symbol
+    fn eval(&self, expr: &Expr, pc: i64) -> Result<i64, String> {
+        Ok(match expr {
+            Expr::Number(value) => *value,
+            Expr::Pc => pc,
+            Expr::Symbol(name) => *self.symbols.get(name).ok_or_else(|| name.clone())?,
+            Expr::Unary(op, expr) => {
+                let value = self.eval(expr, pc)?;
+                match op {
+                    '-' => value.wrapping_neg(),
+                    '<' => value & 0xFF,
+                    _ => (value >> 8) & 0xFF,
+                }
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (self.eval(lhs, pc)?, self.eval(rhs, pc)?);
+                match *op {
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" => lhs.checked_div(rhs).unwrap_or(0),
+                    "%" => lhs.checked_rem(rhs).unwrap_or(0),
+                    "<<" => lhs.wrapping_shl(rhs as u32),
+                    ">>" => lhs.wrapping_shr(rhs as u32),
+                    "&" => lhs & rhs,
+                    "^" => lhs ^ rhs,
+                    _ => lhs | rhs,
+                }
+            }
+        })
+    }
+}
+
+struct Pass {
+    pc: usize,
+    bytes: Vec<u8>,
+    opcodes: Vec<&'static OpCode>,
+}
+
+impl Pass {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+        self.pc += bytes.len();
+    }
+}
+
+// Negative values down to -128 are allowed and wrap
+fn byte(value: i64, line: usize) -> Result<u8, AsmError> {
+    match value {
+        -0x80..=0xFF => Ok(value as u8),
+        _ => Err(AsmError::OutOfRange { line, value }),
+    }
+}
+
+fn word(value: i64, line: usize) -> Result<u16, AsmError> {
+    match value {
+        -0x8000..=0xFFFF => Ok(value as u16),
+        _ => Err(AsmError::OutOfRange { line, value }),
+    }
+}
+
+// Assembles `source` for `origin`, see Assembler
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    Assembler::new(origin).assemble(source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::disasm::Disassembler;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            SCREEN = $0200
+            start:  LDX #<(SCREEN + 1)   ; comment
+                    LDA table,X
+                    STA SCREEN,Y
+                    STA z:$10
+                    STA a:$10
+            loop:   ASL A
+                    BNE loop
+                    LDA ($20),Y
+                    LDA ($20,X)
+                    JMP (vector)
+            table:  .byte 1, \"AB\", >SCREEN
+            vector: .word start, * + 2
+        ";
+        let bytes = assemble(source, 0x8000).unwrap();
+
+        assert_eq!(bytes, vec![
+            0xA2, 0x01, 0xBD, 0x17, 0x80, 0x99, 0x00, 0x02, 0x85, 0x10, 0x8D, 0x10, 0x00,
+            0x0A, 0xD0, 0xFD, 0xB1, 0x20, 0xA1, 0x20, 0x6C, 0x1B, 0x80,
+            0x01, 0x41, 0x42, 0x02, 0x00, 0x80, 0x1F, 0x80,
+        ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(assemble("LDA missing", 0), Err(AsmError::UndefinedSymbol { line: 1, name: "missing".to_string() }));
+        assert_eq!(assemble("STA #1", 0), Err(AsmError::UnknownInstruction { line: 1, text: "STA".to_string() }));
+        assert_eq!(assemble("a: NOP\na: NOP", 0), Err(AsmError::DuplicateSymbol { line: 2, name: "a".to_string() }));
+        assert_eq!(assemble("BNE far\n.res 200\nfar:", 0), Err(AsmError::BranchOutOfRange { line: 1, target: 202 }));
+        assert_eq!(assemble("BNE $10002", 0), Err(AsmError::BranchOutOfRange { line: 1, target: 0x10002 }));
+        assert!(assemble("LDA #-(-$7FFFFFFFFFFFFFFF - 1)", 0).is_err());
+    }
+
+    // What `famemu disasm` writes has to assemble back to the same bytes
+    #[test]
+    fn test_disassembly_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes");
+        let rom = Rom::new(&std::fs::read(path).unwrap()).unwrap();
+
+        let source = Disassembler::new(&rom.prg_rom, 0xC000).to_ca65();
+        assert_eq!(assemble(&source, 0xC000).unwrap(), rom.prg_rom);
+    }
+}
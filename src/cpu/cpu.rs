@@ -1,6 +1,7 @@
 use crate::bus::Bus;
 use crate::cpu::opcodes;
 use crate::cpu::mem::Mem;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 use std::collections::HashMap;
 
@@ -49,6 +50,24 @@ bitflags::bitflags! {
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xFD;
+pub const PROGRAM_START: u16 = 0x0600;
+
+// A change of call depth, for profilers and call stacks. Addresses are the
+// instruction that caused it and where execution went
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallEvent {
+    // JSR
+    Call { from: u16, to: u16 },
+    // RTS
+    Return { from: u16, to: u16 },
+    // Entering a handler through `vector`. Only reset so far, there's no NMI
+    // or IRQ source yet and BRK halts instead
+    Interrupt { vector: u16, from: u16, to: u16 },
+    // RTI
+    ReturnFromInterrupt { from: u16, to: u16 },
+}
+
+pub const RESET_VECTOR: u16 = 0xFFFC;
 
 pub struct CPU {
     pub register_a: u8,
@@ -58,6 +77,7 @@ pub struct CPU {
     pub program_counter: u16,
     pub stack_pointer: u8,
     pub bus: Bus,
+    call_event: Option<CallEvent>,
 }
 
 impl Mem for CPU {
@@ -79,73 +99,98 @@ impl CPU {
             status: CpuFlags::from_bits_truncate(0b100100),
             program_counter: 0,
             stack_pointer: STACK_RESET,
-            bus: bus
+            bus,
+            call_event: None,
         }
     }
     
     pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
+        self.resolve_address(mode, addr, |addr| self.bus.fetch(addr), |addr| self.mem_read(addr))
+    }
+
+    // The same address without a read's side effects, for tracers and loggers
+    pub fn peek_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
+        self.resolve_address(mode, addr, |addr| self.bus.peek(addr), |addr| self.bus.peek(addr))
+    }
+
+    // Operand bytes go through `fetch`, pointers in zero page through `read`
+    fn resolve_address<F, R>(&self, mode: &AddressingMode, addr: u16, fetch: F, read: R) -> u16
+    where
+        F: Fn(u16) -> u8,
+        R: Fn(u16) -> u8,
+    {
+        let fetch_u16 = |addr: u16| u16::from_le_bytes([fetch(addr), fetch(addr.wrapping_add(1))]);
+
         match mode {
-            AddressingMode::ZeroPage => self.mem_read(addr) as u16,
+            AddressingMode::ZeroPage => fetch(addr) as u16,
 
-            AddressingMode::Absolute => self.mem_read_u16(addr),
+            AddressingMode::Absolute => fetch_u16(addr),
 
             AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                
-                addr
+                let pos = fetch(addr);
+
+                pos.wrapping_add(self.register_x) as u16
             }
 
             AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                
-                addr
+                let pos = fetch(addr);
+
+                pos.wrapping_add(self.register_y) as u16
             }
 
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_x as u16);
-                
-                addr
+                let base = fetch_u16(addr);
+
+                base.wrapping_add(self.register_x as u16)
             }
 
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_y as u16);
-                
-                addr
+                let base = fetch_u16(addr);
+
+                base.wrapping_add(self.register_y as u16)
             }
 
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(addr);
+                let base = fetch(addr);
                 
                 let ptr = base.wrapping_add(self.register_x);
 
-                let low = self.mem_read(ptr as u16);
-                let high = self.mem_read(ptr.wrapping_add(1) as u16);
+                let low = read(ptr as u16);
+                let high = read(ptr.wrapping_add(1) as u16);
                 
                 (high as u16) << 8 | (low as u16)
             }
 
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(addr);
+                let base = fetch(addr);
 
-                let low = self.mem_read(base as u16);
-                let high = self.mem_read(base.wrapping_add(1) as u16);
+                let low = read(base as u16);
+                let high = read(base.wrapping_add(1) as u16);
 
                 let deref_base = (high as u16) << 8 | (low as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                
-                deref
+
+                deref_base.wrapping_add(self.register_y as u16)
             }
 
-            AddressingMode::NoneAddressing | _ => {
+            _ => {
                 panic!("AddressingMode {:?} isn't supported!", mode);
             }
         }
     }
 
+    fn fetch_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.bus.fetch(addr), self.bus.fetch(addr.wrapping_add(1))])
+    }
+
+    // Immediate operands are part of the instruction, the rest are data reads
+    fn read_operand(&self, mode: &AddressingMode) -> u8 {
+        match mode {
+            AddressingMode::Immediate => self.bus.fetch(self.program_counter),
+
+            _ => self.mem_read(self.get_absolute_address(mode, self.program_counter))
+        }
+    }
+
     fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter,
@@ -214,22 +259,19 @@ impl CPU {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         
         self.add_to_register_a(value);
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr) as i8;
+        let value = self.read_operand(mode) as i8;
 
         self.add_to_register_a(value.wrapping_neg().wrapping_sub(1) as u8);
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.set_register_a(value & self.register_a);
     }
@@ -258,8 +300,7 @@ impl CPU {
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         let and = value & self.register_a;
         
         self.set_status(CpuFlags::ZERO, and == 0);
@@ -269,18 +310,21 @@ impl CPU {
 
     fn branch(&mut self, condition: bool) {
         if condition {
-            let jump = self.mem_read(self.program_counter) as i8;
+            let jump = self.bus.fetch(self.program_counter) as i8;
             let jump_addr = self.program_counter
                             .wrapping_add(1)
                             .wrapping_add(jump as u16);
+
+            // +1 cycle if branch succeeds, +2 if to a new page
+            let page_crossed = self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00;
+            self.bus.tick(if page_crossed { 2 } else { 1 });
         
             self.program_counter = jump_addr;
         }
     }
 
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.set_status(CpuFlags::CARRY, value <= compare_with);
 
@@ -309,8 +353,7 @@ impl CPU {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.set_register_a(value ^ self.register_a);
     }
@@ -337,7 +380,7 @@ impl CPU {
     }
 
     fn jmp_absolute(&mut self) {
-        let addr = self.mem_read_u16(self.program_counter);
+        let addr = self.fetch_u16(self.program_counter);
         self.program_counter = addr; 
     }
 
@@ -348,7 +391,7 @@ impl CPU {
         // to $4080 rather than $5080 as you intended
         // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
 
-        let addr = self.mem_read_u16(self.program_counter);
+        let addr = self.fetch_u16(self.program_counter);
         let indirect_ref = if addr & 0x00FF == 0x00FF {
             let low = self.mem_read(addr);
             let high = self.mem_read(addr & 0xFF00);
@@ -363,28 +406,25 @@ impl CPU {
 
     fn jsr(&mut self) {
         self.stack_push_u16(self.program_counter + 1);
-        let target_addr = self.mem_read_u16(self.program_counter);
+        let target_addr = self.fetch_u16(self.program_counter);
         self.program_counter = target_addr;
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.set_register_a(value);
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.register_x = value;
         self.update_zero_and_negative_flags(self.register_x);
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.register_y = value;
         self.update_zero_and_negative_flags(self.register_y);
@@ -468,8 +508,7 @@ impl CPU {
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.set_register_a(value | self.register_a);
     }
@@ -578,25 +617,71 @@ impl CPU {
         self.set_status(CpuFlags::NEGATIVE, result >> 7 == 1);
     }
 
-    pub fn reset(&mut self) {
+    pub fn power_on(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
         self.stack_pointer = STACK_RESET;
         self.status = CpuFlags::from_bits_truncate(0b100100);
 
-        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.enter_reset();
+    }
+
+    // The reset line runs the interrupt sequence with writes suppressed: the
+    // stack pointer still moves down by 3, registers and RAM are left alone
+    pub fn reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+
+        self.enter_reset();
+    }
+
+    // The reset sequence takes 7 cycles, like an interrupt
+    fn enter_reset(&mut self) {
+        let from = self.program_counter;
+        self.program_counter = self.mem_read_u16(RESET_VECTOR);
+        self.call_event = Some(CallEvent::Interrupt { vector: RESET_VECTOR, from, to: self.program_counter });
+        self.bus.tick(7);
+    }
+
+    // What the last instruction or reset did to the call depth
+    pub fn call_event(&self) -> Option<CallEvent> {
+        self.call_event
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.register_a);
+        state.u8(self.register_x);
+        state.u8(self.register_y);
+        state.u8(self.status.bits());
+        state.u16(self.program_counter);
+        state.u8(self.stack_pointer);
+    }
+
+    // Registers only, the bus has its own section
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register_a = state.u8()?;
+        self.register_x = state.u8()?;
+        self.register_y = state.u8()?;
+        self.status = CpuFlags::from_bits_truncate(state.u8()?);
+        self.program_counter = state.u16()?;
+        self.stack_pointer = state.u8()?;
+        Ok(())
     }
 
+    // Runs `program` from PROGRAM_START in RAM until BRK. The reset vector is
+    // in cartridge ROM, so PC is pointed at the program directly
     pub fn load_and_run(&mut self, program: Vec<u8>) {
         self.load(program);
-        self.reset();
+        self.power_on();
+        self.program_counter = PROGRAM_START;
         self.run();
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
-        //self.memory[0x0600 .. (0x0600 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xFFFC, 0x0600);
+        for (i, byte) in program.iter().enumerate() {
+            self.mem_write(PROGRAM_START + i as u16, *byte);
+        }
     }
 
     pub fn run(&mut self) {
@@ -607,209 +692,229 @@ impl CPU {
     where 
         F: FnMut(&mut CPU)
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
         loop {
             callback(self);
 
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let current_program_counter_state = self.program_counter;
+            if !self.step() {
+                return;
+            }
+        }
+    }
 
-            let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} wasn't recognized!", code));
-            //let opcode = opcodes.get(&code).unwrap();
+    // Executes one instruction, returns false when the CPU hit BRK
+    pub fn step(&mut self) -> bool {
+        let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP;
 
-            match code {
-                // BRK
-                0x00 => return,
-                
-                // NOP
-                0xEA => {},
+        let code = self.bus.fetch(self.program_counter);
+        self.program_counter += 1;
+        let current_program_counter_state = self.program_counter;
+        self.call_event = None;
 
-                0x8A => self.txa(),
+        let opcode = opcodes.get(&code).unwrap_or_else(|| panic!("OpCode {:x} wasn't recognized!", code));
+        //let opcode = opcodes.get(&code).unwrap();
 
-                0xAA => self.tax(),
-                
-                0xE8 => self.inx(),
+        match code {
+            // BRK
+            0x00 => return false,
+            
+            // NOP
+            0xEA => {},
 
-                0xCA => self.dex(),
-                
-                0xA8 => self.tay(),
+            0x8A => self.txa(),
 
-                0x98 => self.tya(),
+            0xAA => self.tax(),
+            
+            0xE8 => self.inx(),
 
-                0x88 => self.dey(),
+            0xCA => self.dex(),
+            
+            0xA8 => self.tay(),
 
-                0xC8 => self.iny(),
+            0x98 => self.tya(),
 
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
+            0x88 => self.dey(),
 
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
-                
-                0x0A => self.asl_accumulator(),
-                
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&opcode.mode);
-                }
+            0xC8 => self.iny(),
 
-                0x24 | 0x2C => {
-                    self.bit(&opcode.mode);
-                }
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
 
-                // BPL
-                0x10 => self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
-                
-                // BMI
-                0x30 => self.branch(self.status.contains(CpuFlags::NEGATIVE)),
-                
-                // BVC
-                0x50 => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
-                
-                // BVS
-                0x70 => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
-                
-                // BCC
-                0x90 => self.branch(!self.status.contains(CpuFlags::CARRY)),
-                
-                // BCS
-                0xB0 => self.branch(self.status.contains(CpuFlags::CARRY)),
-                
-                // BNE
-                0xD0 => self.branch(!self.status.contains(CpuFlags::ZERO)),
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
+            
+            0x0A => self.asl_accumulator(),
+            
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.asl(&opcode.mode);
+            }
 
-                // BEQ
-                0xF0 => self.branch(self.status.contains(CpuFlags::ZERO)),
+            0x24 | 0x2C => {
+                self.bit(&opcode.mode);
+            }
 
-                // CMP
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
+            // BPL
+            0x10 => self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
+            
+            // BMI
+            0x30 => self.branch(self.status.contains(CpuFlags::NEGATIVE)),
+            
+            // BVC
+            0x50 => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
+            
+            // BVS
+            0x70 => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
+            
+            // BCC
+            0x90 => self.branch(!self.status.contains(CpuFlags::CARRY)),
+            
+            // BCS
+            0xB0 => self.branch(self.status.contains(CpuFlags::CARRY)),
+            
+            // BNE
+            0xD0 => self.branch(!self.status.contains(CpuFlags::ZERO)),
 
-                // CPX
-                0xE0 | 0xE4 | 0xEC => {
-                    self.compare(&opcode.mode, self.register_x);
-                }
+            // BEQ
+            0xF0 => self.branch(self.status.contains(CpuFlags::ZERO)),
 
-                // CPY
-                0xC0 | 0xC4 | 0xCC => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
+            // CMP
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
 
-                0xC6 | 0xD6 | 0xCE | 0xDE => {
-                    self.dec(&opcode.mode);
-                }
+            // CPX
+            0xE0 | 0xE4 | 0xEC => {
+                self.compare(&opcode.mode, self.register_x);
+            }
 
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
+            // CPY
+            0xC0 | 0xC4 | 0xCC => {
+                self.compare(&opcode.mode, self.register_y);
+            }
 
-                // CLC
-                0x18 => self.set_status(CpuFlags::CARRY, false),
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.dec(&opcode.mode);
+            }
 
-                // SEC
-                0x38 => self.set_status(CpuFlags::CARRY, true),
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
 
-                // CLI
-                0x58 => self.set_status(CpuFlags::INTERRUPT_DISABLE, false),
+            // CLC
+            0x18 => self.set_status(CpuFlags::CARRY, false),
 
-                // SEI
-                0x78 => self.set_status(CpuFlags::INTERRUPT_DISABLE, true),
+            // SEC
+            0x38 => self.set_status(CpuFlags::CARRY, true),
 
-                // CLV
-                0xB8 => self.set_status(CpuFlags::OVERFLOW, false),
+            // CLI
+            0x58 => self.set_status(CpuFlags::INTERRUPT_DISABLE, false),
 
-                // CLD
-                0xD8 => self.set_status(CpuFlags::DECIMAL_MODE, false),
+            // SEI
+            0x78 => self.set_status(CpuFlags::INTERRUPT_DISABLE, true),
 
-                // SED
-                0xF8 => self.set_status(CpuFlags::DECIMAL_MODE, true),
+            // CLV
+            0xB8 => self.set_status(CpuFlags::OVERFLOW, false),
 
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&opcode.mode);
-                }
+            // CLD
+            0xD8 => self.set_status(CpuFlags::DECIMAL_MODE, false),
 
-                0x4C => self.jmp_absolute(),
+            // SED
+            0xF8 => self.set_status(CpuFlags::DECIMAL_MODE, true),
 
-                0x6C => self.jmp_indirect(),
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.inc(&opcode.mode);
+            }
 
-                0x20 => self.jsr(),
+            0x4C => self.jmp_absolute(),
 
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
-                    self.lda(&opcode.mode);
-                }
-                
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
-                    self.ldx(&opcode.mode);
-                }
+            0x6C => self.jmp_indirect(),
 
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
-                    self.ldy(&opcode.mode);
-                }
+            0x20 => self.jsr(),
 
-                0x4A => self.lsr_accumulator(),
-                
-                0x46 | 0x56 | 0x4E | 0x5E => {
-                    self.lsr(&opcode.mode);
-                }
-                
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
+                self.lda(&opcode.mode);
+            }
+            
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                self.ldx(&opcode.mode);
+            }
 
-                0x2A => self.rol_accumulator(),
-                
-                0x26 | 0x36 | 0x2E | 0x3E => {
-                    self.rol(&opcode.mode);
-                }
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                self.ldy(&opcode.mode);
+            }
 
-                0x6A => self.ror_accumulator(),
+            0x4A => self.lsr_accumulator(),
+            
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                self.lsr(&opcode.mode);
+            }
+            
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
 
-                0x66 | 0x76 | 0x6E | 0x7E => {
-                    self.ror(&opcode.mode);
-                }
+            0x2A => self.rol_accumulator(),
+            
+            0x26 | 0x36 | 0x2E | 0x3E => {
+                self.rol(&opcode.mode);
+            }
 
-                0x40 => self.rti(),
+            0x6A => self.ror_accumulator(),
 
-                0x60 => self.rts(),
+            0x66 | 0x76 | 0x6E | 0x7E => {
+                self.ror(&opcode.mode);
+            }
 
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
-                    self.sbc(&opcode.mode);
-                }
+            0x40 => self.rti(),
 
-                0x9A => self.txs(),
-                
-                0xBA => self.tsx(),
-                
-                0x48 => self.pha(),
-                
-                0x68 => self.pla(),
-                
-                0x08 => self.php(),
+            0x60 => self.rts(),
 
-                0x28 => self.plp(),
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
+                self.sbc(&opcode.mode);
+            }
 
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+            0x9A => self.txs(),
+            
+            0xBA => self.tsx(),
+            
+            0x48 => self.pha(),
+            
+            0x68 => self.pla(),
+            
+            0x08 => self.php(),
 
-                0x86 | 0x96 | 0x8E => {
-                    self.stx(&opcode.mode);
-                }
-                
-                0x84 | 0x94 | 0x8C => {
-                    self.sty(&opcode.mode);
-                }
+            0x28 => self.plp(),
 
-                _ => todo!()
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
             }
 
-            if current_program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+            0x86 | 0x96 | 0x8E => {
+                self.stx(&opcode.mode);
+            }
+            
+            0x84 | 0x94 | 0x8C => {
+                self.sty(&opcode.mode);
             }
+
+            _ => todo!()
         }
+
+        if current_program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+
+        let from = current_program_counter_state - 1;
+        self.call_event = match code {
+            0x20 => Some(CallEvent::Call { from, to: self.program_counter }),
+            0x60 => Some(CallEvent::Return { from, to: self.program_counter }),
+            0x40 => Some(CallEvent::ReturnFromInterrupt { from, to: self.program_counter }),
+            _ => None,
+        };
+
+        self.bus.tick(opcode.cycles);
+
+        true
     }
-}
\ No newline at end of file
+}
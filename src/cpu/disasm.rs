@@ -0,0 +1,349 @@
+use crate::bus::Bus;
+use crate::cpu::cpu::AddressingMode;
+use crate::cpu::opcodes::{self, OpCode};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const ACCUMULATOR_OPS: [u8; 4] = [0x0A, 0x4A, 0x2A, 0x6A];
+const BRK: u8 = 0x00;
+pub const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const JMP: u8 = 0x4C;
+const RTS: u8 = 0x60;
+pub const JMP_INDIRECT: u8 = 0x6C;
+// NMI, reset and IRQ vectors at the very top of the address space
+const VECTORS: u16 = 0xFFFA;
+const BYTES_PER_LINE: usize = 8;
+
+// One decoded instruction. `operand` is the byte or little endian word after
+// the opcode, zero for one byte instructions
+#[derive(Clone, Copy)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: &'static OpCode,
+    pub operand: u16,
+}
+
+impl Instruction {
+    // Decodes the instruction at `offset` into `data`, which is mapped at
+    // `base`. None if the instruction runs past the end of the slice
+    pub fn decode(data: &[u8], offset: usize, base: u16) -> Option<Instruction> {
+        let opcode = opcodes::OPCODES_MAP[data.get(offset)?];
+        let bytes = data.get(offset + 1..offset + opcode.len as usize)?;
+        let operand = bytes.iter().rev().fold(0, |word, byte| word << 8 | *byte as u16);
+
+        Some(Instruction { addr: base.wrapping_add(offset as u16), opcode, operand })
+    }
+
+    // Length in bytes
+    pub fn size(&self) -> u16 {
+        self.opcode.len as u16
+    }
+
+    pub fn is_official(&self) -> bool {
+        !self.opcode.mnemonic.starts_with('*')
+    }
+
+    fn is_branch(&self) -> bool {
+        self.opcode.len == 2 && matches!(self.opcode.mode, AddressingMode::NoneAddressing)
+    }
+
+    // Where a branch, JMP or JSR goes
+    pub fn target(&self) -> Option<u16> {
+        if self.is_branch() {
+            Some(self.addr.wrapping_add(2).wrapping_add(self.operand as u8 as i8 as u16))
+        } else if matches!(self.opcode.code, JMP | JSR) {
+            Some(self.operand)
+        } else {
+            None
+        }
+    }
+
+    // True if execution never falls through to the next instruction. BRK
+    // halts the CPU in this emulator, so it ends the flow as well
+    pub fn ends_flow(&self) -> bool {
+        matches!(self.opcode.code, BRK | RTI | JMP | RTS | JMP_INDIRECT)
+    }
+
+    // Address the operand refers to, for label substitution
+    fn operand_addr(&self) -> Option<u16> {
+        match self.opcode.mode {
+            AddressingMode::Immediate => None,
+            AddressingMode::NoneAddressing if self.opcode.code == JMP_INDIRECT => Some(self.operand),
+            AddressingMode::NoneAddressing => self.target(),
+            _ => Some(self.operand),
+        }
+    }
+
+    // ca65 syntax. `symbol` may name the operand's address, `force_absolute`
+    // adds ca65's `a:` so absolute operands below $100 keep their length
+    pub fn format<F: Fn(u16) -> Option<String>>(&self, symbol: F, force_absolute: bool) -> String {
+        let byte = self.operand as u8;
+        let word = self.operand;
+        let name = |width: usize| {
+            let addr = self.operand_addr().unwrap_or(word);
+            symbol(addr).unwrap_or_else(|| format!("${:01$X}", addr, width))
+        };
+        let prefix = if force_absolute && word < 0x100 { "a:" } else { "" };
+
+        let operand = match self.opcode.mode {
+            AddressingMode::Immediate => format!("#${:02X}", byte),
+            AddressingMode::ZeroPage => name(2),
+            AddressingMode::ZeroPage_X => format!("{},X", name(2)),
+            AddressingMode::ZeroPage_Y => format!("{},Y", name(2)),
+            AddressingMode::Absolute => format!("{}{}", prefix, name(4)),
+            AddressingMode::Absolute_X => format!("{}{},X", prefix, name(4)),
+            AddressingMode::Absolute_Y => format!("{}{},Y", prefix, name(4)),
+            AddressingMode::Indirect_X => format!("({},X)", name(2)),
+            AddressingMode::Indirect_Y => format!("({}),Y", name(2)),
+            AddressingMode::NoneAddressing => match self.opcode.len {
+                1 if ACCUMULATOR_OPS.contains(&self.opcode.code) => "A".to_string(),
+                1 => "".to_string(),
+                _ if self.opcode.code == JMP_INDIRECT => format!("({})", name(4)),
+                _ => name(4),
+            },
+        };
+
+        format!("{} {}", self.opcode.mnemonic, operand).trim_end().to_string()
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(|_| None, false))
+    }
+}
+
+// Decodes the instruction at `addr` in memory. Peeks, so looking doesn't
+// trigger watchpoints or shift the controllers
+pub fn decode_at(bus: &Bus, addr: u16) -> Instruction {
+    let len = opcodes::OPCODES_MAP[&bus.peek(addr)].len as u16;
+    let bytes: Vec<u8> = (0..len).map(|i| bus.peek(addr.wrapping_add(i))).collect();
+    Instruction::decode(&bytes, 0, addr).unwrap()
+}
+
+// Disassembles the instruction at `addr`, returns it with its length
+pub fn disassemble_at(bus: &Bus, addr: u16) -> (String, u16) {
+    let instruction = decode_at(bus, addr);
+    (instruction.to_string(), instruction.size())
+}
+
+// Disassembles a block of PRG mapped at `base`. Code is found by following
+// the flow from the entry points, everything not reached is emitted as data.
+// Without entry points every byte is decoded as code
+pub struct Disassembler<'a> {
+    data: &'a [u8],
+    base: u16,
+    entries: Vec<u16>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl<'a> Disassembler<'a> {
+    // A block ending at $FFFF takes its entry points from the vectors
+    pub fn new(data: &'a [u8], base: u16) -> Self {
+        let mut disassembler = Disassembler { data, base, entries: vec![], labels: BTreeMap::new() };
+
+        if let Some(vectors) = disassembler.vectors() {
+            for (i, name) in ["nmi", "reset", "irq"].into_iter().enumerate() {
+                let addr = u16::from_le_bytes([data[vectors + i * 2], data[vectors + i * 2 + 1]]);
+                disassembler.add_entry(addr);
+                if disassembler.offset(addr).is_some() {
+                    disassembler.labels.entry(addr).or_insert(name.to_string());
+                }
+            }
+        }
+
+        disassembler
+    }
+
+    pub fn add_entry(&mut self, addr: u16) {
+        self.entries.push(addr);
+    }
+
+    // Named labels win over the generated ones
+    pub fn add_label(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        (offset < self.data.len()).then_some(offset)
+    }
+
+    // Offset of the vector table if the block ends at $FFFF
+    fn vectors(&self) -> Option<usize> {
+        let end = self.base as usize + self.data.len();
+        (end == 0x10000 && self.data.len() >= 6).then(|| self.offset(VECTORS).unwrap())
+    }
+
+    // Offsets of the instructions reached from the entry points
+    fn find_code(&self) -> BTreeMap<usize, Instruction> {
+        let mut code = BTreeMap::new();
+        let mut claimed = vec![false; self.data.len()];
+
+        if self.entries.is_empty() {
+            let mut offset = 0;
+            while let Some(instruction) = Instruction::decode(self.data, offset, self.base) {
+                if instruction.is_official() {
+                    code.insert(offset, instruction);
+                    offset += instruction.size() as usize;
+                } else {
+                    offset += 1;
+                }
+            }
+            return code;
+        }
+
+        let mut pending: Vec<u16> = self.entries.clone();
+        while let Some(addr) = pending.pop() {
+            let mut offset = match self.offset(addr) {
+                Some(offset) => offset,
+                None => continue,
+            };
+
+            while let Some(instruction) = Instruction::decode(self.data, offset, self.base) {
+                let len = instruction.size() as usize;
+                // Unofficial opcodes are far likelier data than code, and
+                // overlapping instructions can't be written as source
+                if !instruction.is_official() || claimed[offset..offset + len].iter().any(|claimed| *claimed) {
+                    break;
+                }
+                if self.vectors().is_some_and(|vectors| offset + len > vectors) {
+                    break;
+                }
+
+                claimed[offset..offset + len].fill(true);
+                code.insert(offset, instruction);
+                pending.extend(instruction.target());
+
+                if instruction.ends_flow() {
+                    break;
+                }
+                offset += len;
+            }
+        }
+
+        code
+    }
+
+    // Labels for every address in the block that code jumps to or refers to.
+    // Only addresses where a line starts can carry one
+    fn all_labels(&self, code: &BTreeMap<usize, Instruction>) -> BTreeMap<u16, String> {
+        let mut labels = self.labels.clone();
+
+        for instruction in code.values() {
+            let Some(addr) = instruction.operand_addr() else { continue };
+            if self.offset(addr).is_some() && !labels.contains_key(&addr) {
+                let prefix = if instruction.target().is_some() { "L" } else { "D" };
+                labels.insert(addr, format!("{}{:04X}", prefix, addr));
+            }
+        }
+
+        labels.retain(|addr, _| match self.offset(*addr) {
+            Some(offset) => code.contains_key(&offset) || code.range(..offset).next_back().is_none_or(
+                |(start, instruction)| start + instruction.size() as usize <= offset
+            ),
+            None => true,
+        });
+        labels
+    }
+
+    // Labels outside the block or in the vector table become `name = $addr`
+    fn is_equate(&self, addr: u16) -> bool {
+        let end = self.vectors().unwrap_or(self.data.len());
+        self.offset(addr).is_none_or(|offset| offset >= end)
+    }
+
+    // ca65 source that assembles back to the same bytes
+    pub fn to_ca65(&self) -> String {
+        let code = self.find_code();
+        let labels = self.all_labels(&code);
+        let symbol = |addr: u16| labels.get(&addr).cloned();
+        let mut text = String::new();
+
+        writeln!(text, ".setcpu \"6502\"").unwrap();
+        for (addr, name) in labels.iter().filter(|(addr, _)| self.is_equate(**addr)) {
+            writeln!(text, "{} = ${:04X}", name, addr).unwrap();
+        }
+        writeln!(text, "\n.org ${:04X}", self.base).unwrap();
+
+        let end = self.vectors().unwrap_or(self.data.len());
+        let mut data = vec![];
+        let mut offset = 0;
+
+        while offset < end {
+            let addr = self.base.wrapping_add(offset as u16);
+            let instruction = code.get(&offset);
+            let label = labels.get(&addr);
+
+            if !data.is_empty() && (instruction.is_some() || label.is_some() || data.len() == BYTES_PER_LINE) {
+                writeln!(text, "        .byte {}", data.join(",")).unwrap();
+                data.clear();
+            }
+            if let Some(label) = label {
+                writeln!(text, "{}:", label).unwrap();
+            }
+
+            match instruction {
+                Some(instruction) => {
+                    writeln!(text, "        {}", instruction.format(symbol, true)).unwrap();
+                    offset += instruction.size() as usize;
+                }
+                None => {
+                    data.push(format!("${:02X}", self.data[offset]));
+                    offset += 1;
+                }
+            }
+        }
+        if !data.is_empty() {
+            writeln!(text, "        .byte {}", data.join(",")).unwrap();
+        }
+
+        if let Some(vectors) = self.vectors() {
+            let words: Vec<String> = (0..3)
+                .map(|i| u16::from_le_bytes([self.data[vectors + i * 2], self.data[vectors + i * 2 + 1]]))
+                .map(|addr| symbol(addr).unwrap_or_else(|| format!("${:04X}", addr)))
+                .collect();
+            writeln!(text, "\n.org ${:04X}\n        .word {}", VECTORS, words.join(",")).unwrap();
+        }
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ca65_output() {
+        // $C000: LDX #$00; INX; BNE $C002; STA $0010; JMP ($FFFC); two bytes of
+        // data; then the vectors, all pointing at $C000
+        let mut data = vec![0xA2, 0x00, 0xE8, 0xD0, 0xFD, 0x8D, 0x10, 0x00, 0x6C, 0xFC, 0xFF, 0x12, 0x34];
+        data.resize(0x4000 - 6, 0xFF);
+        data.extend_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+
+        let mut disassembler = Disassembler::new(&data, 0xC000);
+        disassembler.add_label(0x0010, "counter");
+        let source = disassembler.to_ca65();
+
+        let expected = "\
+.setcpu \"6502\"
+counter = $0010
+DFFFC = $FFFC
+
+.org $C000
+nmi:
+        LDX #$00
+LC002:
+        INX
+        BNE LC002
+        STA a:counter
+        JMP (DFFFC)
+        .byte $12,$34,$FF,$FF,$FF,$FF,$FF,$FF
+";
+        assert!(source.starts_with(expected), "{}", source);
+        assert!(source.ends_with(".org $FFFA\n        .word nmi,nmi,nmi\n"));
+    }
+}
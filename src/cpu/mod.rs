@@ -1,63 +1,76 @@
+#[allow(clippy::module_inception)]
 pub mod cpu;
 pub mod opcodes;
 pub mod mem;
 pub mod trace;
+pub mod disasm;
+pub mod asm;
 
-// #[cfg(test)]
-// mod test {
-//     use super::cpu::CPU;
-//     use crate::cpu::mem::Mem;
-
-//     #[test]
-//     fn test_0xa9_lda_immediate_load_data() {
-//         let mut cpu = CPU::new();
-//         cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
-//         assert_eq!(cpu.register_a, 5);
-//         // let s = cpu.status;
-//         // println!("{}", format!("{s:#b}"));
-//         assert!(cpu.status.bits() & 0b0000_0010 == 0);
-//         assert!(cpu.status.bits() & 0b1000_0000 == 0);
-//     }
-
-//     #[test]
-//     fn test_0xa9_lda_zero_flag() {
-//         let mut cpu = CPU::new();
-//         cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
-//         assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
-//     }
-
-//     #[test]
-//     fn test_0xaa_tax_move_a_to_x() {
-//         let mut cpu = CPU::new();
-//         cpu.load_and_run(vec![0xa9, 0x0a, 0xaa, 0x00]);
-
-//         assert_eq!(cpu.register_x, 10);
-//     }
-
-//     #[test]
-//     fn test_5_ops_working_together() {
-//         let mut cpu = CPU::new();
-//         cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
-
-//         assert_eq!(cpu.register_x, 0xc1);
-//     }
-
-//     #[test]
-//     fn test_inx_overflow() {
-//         let mut cpu = CPU::new();
-//         cpu.load_and_run(vec![0xa9, 0xff, 0xaa, 0xe8, 0xe8, 0x00]);
-
-//         assert_eq!(cpu.register_x, 1);
-//     }
-
-//     #[test]
-//     fn test_lda_from_memory() {
-//         let mut cpu = CPU::new();
-//         //cpu.mem_write(0x10, 0x55);
-//         cpu.mem_write(0x10, 0x55);
-        
-//         cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
-
-//         assert_eq!(cpu.register_a, 0x55);
-//     }
-// }
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use super::asm::assemble;
+    use super::cpu::{CPU, PROGRAM_START};
+    use crate::bus::Bus;
+    use crate::cpu::mem::Mem;
+    use crate::rom::Rom;
+
+    fn cpu() -> CPU {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.resize(16 + 0x4000 + 0x2000, 0);
+        CPU::new(Bus::new(Rom::new(&raw).unwrap()))
+    }
+
+    fn run(cpu: &mut CPU, source: &str) {
+        cpu.load_and_run(assemble(source, PROGRAM_START).unwrap());
+    }
+
+    #[test]
+    fn test_0xa9_lda_immediate_load_data() {
+        let mut cpu = cpu();
+        run(&mut cpu, "LDA #$05\nBRK");
+        assert_eq!(cpu.register_a, 5);
+        assert!(cpu.status.bits() & 0b0000_0010 == 0);
+        assert!(cpu.status.bits() & 0b1000_0000 == 0);
+    }
+
+    #[test]
+    fn test_0xa9_lda_zero_flag() {
+        let mut cpu = cpu();
+        run(&mut cpu, "LDA #$00\nBRK");
+        assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
+    }
+
+    #[test]
+    fn test_0xaa_tax_move_a_to_x() {
+        let mut cpu = cpu();
+        run(&mut cpu, "LDA #10\nTAX\nBRK");
+
+        assert_eq!(cpu.register_x, 10);
+    }
+
+    #[test]
+    fn test_5_ops_working_together() {
+        let mut cpu = cpu();
+        run(&mut cpu, "LDA #$C0\nTAX\nINX\nBRK");
+
+        assert_eq!(cpu.register_x, 0xc1);
+    }
+
+    #[test]
+    fn test_inx_overflow() {
+        let mut cpu = cpu();
+        run(&mut cpu, "LDA #$FF\nTAX\nINX\nINX\nBRK");
+
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_lda_from_memory() {
+        let mut cpu = cpu();
+        cpu.mem_write(0x10, 0x55);
+
+        run(&mut cpu, "LDA $10\nBRK");
+
+        assert_eq!(cpu.register_a, 0x55);
+    }
+}
@@ -1,67 +1,75 @@
 use crate::cpu::cpu::{AddressingMode, CPU};
 use crate::cpu::opcodes;
-use crate::cpu::mem::Mem;
 
 use std::collections::HashMap;
 use std::format;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+pub fn trace(cpu: &CPU) -> String {
+    let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP;
     
     let start = cpu.program_counter;
+    // Logging mustn't shift the controllers or trip watchpoints
+    let peek = |addr: u16| cpu.bus.peek(addr);
+    let peek_u16 = |addr: u16| u16::from_le_bytes([peek(addr), peek(addr.wrapping_add(1))]);
 
-    let code = cpu.mem_read(start);
-    let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} wasn't recognized!", code));
+    let code = peek(start);
+    let opcode = opcodes.get(&code).unwrap_or_else(|| panic!("OpCode {:x} wasn't recognized!", code));
     
     let mut hex_dump = vec![code];
 
+    // Operand addresses are shown by their label when one is loaded
+    let name = |addr: u16, width: usize| match cpu.bus.label(addr) {
+        Some(label) => label.to_string(),
+        None => format!("${:01$X}", addr, width),
+    };
+
     let (mem_addr, stored_value) = match opcode.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
 
         _ => {
-            let addr = cpu.get_absolute_address(&opcode.mode, start + 1);
+            let addr = cpu.peek_absolute_address(&opcode.mode, start + 1);
 
-            (addr, cpu.mem_read(addr))
+            (addr, peek(addr))
         }
     };
 
     let asm_opcode_with_address = match opcode.len {
 
         1 => match opcode.code {
-            0x0A | 0x4A | 0x2A | 0x6A => format!("A "),
+            0x0A | 0x4A | 0x2A | 0x6A => "A ".to_string(),
             _ => "".to_string()
         },
         2 => {
-            let addr = cpu.mem_read(start + 1);
+            let addr = peek(start + 1);
             hex_dump.push(addr);
 
             match opcode.mode {
                 AddressingMode::Immediate => format!("#${:02X}", addr),
                 
-                AddressingMode::ZeroPage => format!("${:02X} = {:02X}", mem_addr, stored_value),
+                AddressingMode::ZeroPage => format!("{} = {:02X}", name(mem_addr, 2), stored_value),
                 
                 AddressingMode::ZeroPage_X => format!(
-                        "${:02X},X @{:02X} = {:02X}",
-                        addr, mem_addr, stored_value
+                        "{},X @{:02X} = {:02X}",
+                        name(addr as u16, 2), mem_addr, stored_value
                 ),
                 AddressingMode::ZeroPage_Y => format!(
-                        "${:02X},Y @{:02X} = {:02X}",
-                        addr, mem_addr, stored_value
+                        "{},Y @{:02X} = {:02X}",
+                        name(addr as u16, 2), mem_addr, stored_value
                 ),
                 AddressingMode::Indirect_X => format!(
-                        "(${:02X},X) @{:02X} = {:04X} = {:02X}",
-                        addr, (addr.wrapping_add(cpu.register_x)), mem_addr, stored_value
+                        "({},X) @{:02X} = {:04X} = {:02X}",
+                        name(addr as u16, 2), (addr.wrapping_add(cpu.register_x)), mem_addr, stored_value
                 ),
                 AddressingMode::Indirect_Y => format!(
-                        "(${:02X}),Y = {:04X} @{:04X} = {:02X}",
-                        addr, (addr.wrapping_add(cpu.register_y)), mem_addr, stored_value
+                        "({}),Y = {:04X} @ {:04X} = {:02X}",
+                        name(addr as u16, 2), mem_addr.wrapping_sub(cpu.register_y as u16), mem_addr, stored_value
                 ),
                 AddressingMode::NoneAddressing => {
                     // Operations like JMP, BNE, BNQ, etc
 
                     let addr = (start as usize + 2).wrapping_add((addr as i8) as usize);
 
-                    format!("${:04X}", addr)
+                    name(addr as u16, 4)
                 }
 
                 _ => panic!(
@@ -71,43 +79,43 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         },
         3 => {
-            let low = cpu.mem_read(start + 1);
-            let high = cpu.mem_read(start + 2);
+            let low = peek(start + 1);
+            let high = peek(start + 2);
         
             hex_dump.push(low);
             hex_dump.push(high);
         
-            let addr = cpu.mem_read_u16(start + 1);
+            let addr = peek_u16(start + 1);
 
             match opcode.mode {
                 AddressingMode::NoneAddressing => {
                     // JMP indirect
                     if opcode.code == 0x6C {
                         let jmp_addr = if addr & 0x00FF == 0x00FF {
-                            let low = cpu.mem_read(addr);
-                            let high = cpu.mem_read(addr & 0x00FF);
+                            let low = peek(addr);
+                            let high = peek(addr & 0x00FF);
                             (high as u16) << 8 | (low as u16)
                         }
                         else {
-                            cpu.mem_read_u16(addr)
+                            peek_u16(addr)
                         };
 
-                        format!("(${:04X}) = {:04X}", addr, jmp_addr)
+                        format!("({}) = {:04X}", name(addr, 4), jmp_addr)
                     }
                     else {
-                        format!("${:04X}", addr)
+                        name(addr, 4)
                     }
                 }
                 AddressingMode::Absolute => format!(
-                    "${:04X} = {:02X}", mem_addr, stored_value
+                    "{} = {:02X}", name(mem_addr, 4), stored_value
                 ),
                 AddressingMode::Absolute_X => format!(
-                    "${:04X},X @ {:04X} = {:02X}",
-                    addr, mem_addr, stored_value
+                    "{},X @ {:04X} = {:02X}",
+                    name(addr, 4), mem_addr, stored_value
                 ),
                 AddressingMode::Absolute_Y => format!(
-                    "${:04X},Y @ {:04X} = {:02X}",
-                    addr, mem_addr, stored_value
+                    "{},Y @ {:04X} = {:02X}",
+                    name(addr, 4), mem_addr, stored_value
                 ),
                 _ => panic!(
                     "Unexpected addressing mode {:?} has ops-len 3. Code {:02X}",
@@ -139,11 +147,13 @@ pub fn trace(cpu: &mut CPU) -> String {
 mod test {
     use super::*;
     use crate::bus::Bus;
+    use crate::cpu::mem::Mem;
     use crate::rom::Rom;
+    use crate::symbols::Symbols;
     use std::fs;
     #[test]
     fn test_format_trace() {
-        let game_code = fs::read("/Users/alexey/Documents/Prog/Rust/FamEmu/nestest.nes").unwrap();
+        let game_code = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
         let rom = Rom::new(&game_code).unwrap();
         let mut bus = Bus::new(rom);
         bus.mem_write(100, 0xa2);
@@ -177,7 +187,7 @@ mod test {
 
     #[test]
     fn test_format_mem_access() {
-        let game_code = fs::read("/Users/alexey/Documents/Prog/Rust/FamEmu/nestest.nes").unwrap();
+        let game_code = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
         let rom = Rom::new(&game_code).unwrap();
         let mut bus = Bus::new(rom);
         // ORA ($33), Y
@@ -185,8 +195,8 @@ mod test {
         bus.mem_write(101, 0x33);
 
         //data
-        bus.mem_write(0x33, 00);
-        bus.mem_write(0x34, 04);
+        bus.mem_write(0x33, 0x00);
+        bus.mem_write(0x34, 0x04);
 
         //target cell
         bus.mem_write(0x400, 0xAA);
@@ -203,4 +213,26 @@ mod test {
             result[0]
         );
     }
+
+    #[test]
+    fn test_format_symbols() {
+        let game_code = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+        let mut bus = Bus::new(Rom::new(&game_code).unwrap());
+        let mut symbols = Symbols::default();
+        symbols.load_nl("$0033#pointer#\n$0400#buffer#\n", None).unwrap();
+        bus.set_symbols(symbols);
+        // ORA ($33), Y; STA $0400
+        for (addr, byte) in [0x11, 0x33, 0x8D, 0x00, 0x04].iter().enumerate() {
+            bus.mem_write(100 + addr as u16, *byte);
+        }
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace(cpu));
+        });
+        assert!(result[0].starts_with("0064  11 33     ORA (pointer),Y = 0000 @ 0000 = 00"));
+        assert!(result[1].starts_with("0066  8D 00 04  STA buffer = 00"));
+    }
 }
\ No newline at end of file
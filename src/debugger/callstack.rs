@@ -0,0 +1,278 @@
+use crate::bus::Bus;
+use crate::cpu::cpu::{CallEvent, CPU, RESET_VECTOR};
+
+use std::fmt::Write as _;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+// 128 return addresses fill the whole stack page, deeper than that the stack
+// pointer has wrapped and the oldest frames are gone anyway
+const MAX_FRAMES: usize = 128;
+// Tricks kept for `backtrace`, oldest are dropped first
+const MAX_TRICKS: usize = 8;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameKind {
+    Subroutine,
+    Interrupt { vector: u16 },
+}
+
+// A JSR or interrupt that hasn't returned yet
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // The JSR, or the instruction the interrupt came before
+    pub call_site: u16,
+    pub entry: u16,
+    // Where RTS or RTI should go
+    pub return_addr: u16,
+    // Stack pointer right after the return address was pushed
+    pub sp: u8,
+}
+
+// Code that uses the stack for something other than plain calls and returns
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StackTrick {
+    // RTS/RTI to an address pushed inside the current frame, i.e. a jump
+    PushedReturn { pc: u16, instruction: &'static str, to: u16 },
+    // RTS/RTI that went up more than one frame
+    Unwound { pc: u16, instruction: &'static str, frames: usize },
+    // RTS/RTI to somewhere other than what the call pushed
+    ChangedReturn { pc: u16, instruction: &'static str, to: u16, expected: u16 },
+    // Pulls or TXS that moved the stack pointer above return addresses
+    Discarded { pc: u16, frames: usize },
+    // RTS/RTI with no call on the shadow stack
+    Unmatched { pc: u16, instruction: &'static str, to: u16 },
+}
+
+impl StackTrick {
+    pub fn describe(&self, bus: &Bus) -> String {
+        match *self {
+            StackTrick::PushedReturn { pc, instruction, to } => format!(
+                "{} at {} went to {} through a pushed address, treated as a jump",
+                instruction, describe_addr(bus, pc), describe_addr(bus, to)
+            ),
+            StackTrick::Unwound { pc, instruction, frames } => format!(
+                "{} at {} returned past {} more frame(s)", instruction, describe_addr(bus, pc), frames
+            ),
+            StackTrick::ChangedReturn { pc, instruction, to, expected } => format!(
+                "{} at {} went to {} instead of {}",
+                instruction, describe_addr(bus, pc), describe_addr(bus, to), describe_addr(bus, expected)
+            ),
+            StackTrick::Discarded { pc, frames } => format!(
+                "{} dropped {} return address(es) off the stack", describe_addr(bus, pc), frames
+            ),
+            StackTrick::Unmatched { pc, instruction, to } => format!(
+                "{} at {} went to {} without a call to return from",
+                instruction, describe_addr(bus, pc), describe_addr(bus, to)
+            ),
+        }
+    }
+}
+
+// A shadow of the calls on the 6502 stack, kept from JSR, RTS, interrupts
+// and RTI as they run rather than read back from $0100-$01FF, so pushed data
+// doesn't get taken for return addresses. BRK halts the CPU in this emulator
+// instead of going through $FFFE, so it never adds a frame
+#[derive(Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    tricks: Vec<StackTrick>,
+    // Where the instruction that ran last was
+    last_pc: u16,
+    last_cycles: usize,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack::default()
+    }
+
+    // Call before every instruction with whatever the previous one left behind
+    pub fn update(&mut self, cpu: &CPU) {
+        let cycles = cpu.bus.cycles();
+        if cycles == self.last_cycles {
+            return;
+        }
+        let pc = std::mem::replace(&mut self.last_pc, cpu.program_counter);
+        // Time went backwards, a save state was loaded or the run rewound.
+        // The frames and the last call event are from another point in the
+        // run, start over
+        if cycles < self.last_cycles {
+            self.last_cycles = cycles;
+            self.frames.clear();
+            return;
+        }
+        self.last_cycles = cycles;
+        let sp = cpu.stack_pointer;
+
+        match cpu.call_event() {
+            Some(CallEvent::Call { from, to }) => {
+                self.push(Frame { kind: FrameKind::Subroutine, call_site: from, entry: to, return_addr: from.wrapping_add(3), sp });
+            }
+            Some(CallEvent::Interrupt { vector: RESET_VECTOR, .. }) => self.frames.clear(),
+            Some(CallEvent::Interrupt { vector, from, to }) => {
+                self.push(Frame { kind: FrameKind::Interrupt { vector }, call_site: from, entry: to, return_addr: from, sp });
+            }
+            Some(CallEvent::Return { from, to }) => self.pop(from, "RTS", to, sp),
+            Some(CallEvent::ReturnFromInterrupt { from, to }) => self.pop(from, "RTI", to, sp),
+            None => {
+                let frames = self.returned(sp);
+                if frames > 0 {
+                    self.frames.truncate(self.frames.len() - frames);
+                    self.note(StackTrick::Discarded { pc, frames });
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    fn pop(&mut self, pc: u16, instruction: &'static str, to: u16, sp: u8) {
+        if self.frames.is_empty() {
+            return self.note(StackTrick::Unmatched { pc, instruction, to });
+        }
+
+        // The return address came off below the innermost frame's
+        let frames = self.returned(sp);
+        if frames == 0 {
+            return self.note(StackTrick::PushedReturn { pc, instruction, to });
+        }
+
+        let frame = self.frames[self.frames.len() - frames];
+        self.frames.truncate(self.frames.len() - frames);
+        if frames > 1 {
+            self.note(StackTrick::Unwound { pc, instruction, frames: frames - 1 });
+        } else if to != frame.return_addr {
+            self.note(StackTrick::ChangedReturn { pc, instruction, to, expected: frame.return_addr });
+        }
+    }
+
+    // Innermost frames whose return address is above `sp`, so already gone
+    fn returned(&self, sp: u8) -> usize {
+        self.frames.iter().rev().take_while(|frame| frame.sp < sp).count()
+    }
+
+    fn note(&mut self, trick: StackTrick) {
+        if self.tricks.len() == MAX_TRICKS {
+            self.tricks.remove(0);
+        }
+        self.tricks.push(trick);
+    }
+
+    // Outermost first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // The most recent last
+    pub fn tricks(&self) -> &[StackTrick] {
+        &self.tricks
+    }
+
+    // Innermost first, like GDB: PC, then where each frame returns to
+    pub fn backtrace(&self, cpu: &CPU) -> String {
+        let bus = &cpu.bus;
+        let mut text = format!("#0  {}", describe_addr(bus, cpu.program_counter));
+
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let from = match frame.kind {
+                FrameKind::Subroutine => format!("{}, called at ${:04X}", describe_addr(bus, frame.entry), frame.call_site),
+                FrameKind::Interrupt { vector } => format!("{} handler {}", vector_name(vector), describe_addr(bus, frame.entry)),
+            };
+            write!(text, "\n#{}  {}  return from {}", i + 1, describe_addr(bus, frame.return_addr), from).unwrap();
+        }
+
+        if !self.tricks.is_empty() {
+            text += "\nStack tricks seen:";
+            for trick in &self.tricks {
+                write!(text, "\n  {}", trick.describe(bus)).unwrap();
+            }
+        }
+        text
+    }
+}
+
+// $0603 <main+3>
+fn describe_addr(bus: &Bus, addr: u16) -> String {
+    match bus.nearest_label(addr) {
+        Some((name, 0)) => format!("${:04X} <{}>", addr, name),
+        Some((name, offset)) => format!("${:04X} <{}+{}>", addr, name, offset),
+        None => format!("${:04X}", addr),
+    }
+}
+
+fn vector_name(vector: u16) -> &'static str {
+    match vector {
+        NMI_VECTOR => "NMI",
+        IRQ_VECTOR => "IRQ",
+        _ => "interrupt",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::debugger::{Debugger, Flow};
+    use crate::nes::test_util::nestest;
+    use crate::nes::Nes;
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn test_backtrace() {
+        let mut nes = nestest();
+        let mut symbols = Symbols::default();
+        symbols.load_nl("$0600#main#\n$0604#outer#\n$060E#inner#\n$0610#dispatch#\n$0618#drop#\n", None).unwrap();
+        nes.bus_mut().set_symbols(symbols);
+
+        // dispatch RTSes to a pushed address, drop pulls its own return
+        // address so its RTS goes straight back to main
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut nes, "asm $0600 JSR outer; BRK");
+        debugger.execute(&mut nes, "asm $0604 JSR inner; JSR dispatch; JSR drop; RTS");
+        debugger.execute(&mut nes, "asm $060E INX; RTS");
+        debugger.execute(&mut nes, "asm $0610 LDA #$06; PHA; LDA #$16; PHA; RTS; RTS");
+        debugger.execute(&mut nes, "asm $0618 PLA; PLA; RTS");
+        nes.cpu_mut().program_counter = 0x0600;
+        debugger.should_break(&nes);
+        let start = crate::savestate::save(&nes);
+
+        let run = |debugger: &mut Debugger, nes: &mut Nes, command: &str| {
+            assert!(matches!(debugger.execute(nes, command).1, Flow::Resume));
+            while !debugger.should_break(nes) && nes.step() {}
+        };
+        debugger.execute(&mut nes, "break inner");
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(
+            debugger.execute(&mut nes, "bt").0,
+            "#0  $060E <inner>\n\
+             #1  $0607 <outer+3>  return from $060E <inner>, called at $0604\n\
+             #2  $0603 <main+3>  return from $0604 <outer>, called at $0600"
+        );
+
+        // Going back to the start leaves nothing to return from
+        crate::savestate::load(&mut nes, &start).unwrap();
+        debugger.should_break(&nes);
+        assert!(debugger.call_stack().frames().is_empty());
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(debugger.call_stack().frames().len(), 2);
+
+        debugger.execute(&mut nes, "delete");
+        run(&mut debugger, &mut nes, "c");
+        let call_stack = debugger.call_stack();
+        assert!(call_stack.frames().is_empty());
+        assert_eq!(call_stack.tricks(), &[
+            StackTrick::PushedReturn { pc: 0x0616, instruction: "RTS", to: 0x0617 },
+            StackTrick::Discarded { pc: 0x0618, frames: 1 },
+        ]);
+        assert_eq!(
+            call_stack.tricks()[0].describe(nes.bus()),
+            "RTS at $0616 <dispatch+6> went to $0617 <dispatch+7> through a pushed address, treated as a jump"
+        );
+    }
+}
@@ -0,0 +1,904 @@
+use crate::bus::watch::{Access, WatchHit};
+use crate::bus::{Bus, CARTRIDGE_ROM};
+use crate::cpu::asm::Assembler;
+use crate::cpu::cpu::{CpuFlags, CPU};
+use crate::cpu::disasm::{decode_at, disassemble_at, JSR};
+use crate::cpu::mem::Mem;
+use crate::headless::parse_number;
+use crate::nes::Nes;
+use crate::symbols::PRG_BANK_SIZE;
+use crate::tracer::{self, TraceFormat};
+
+use callstack::CallStack;
+
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+pub mod callstack;
+
+const HELP: &str = "\
+break ADDR [if COND]   stop before ADDR executes, optionally only when COND holds
+break if COND          stop when COND becomes true, e.g. `a == $10 && cycles >= 5000`
+                       operands: a x y sp pc p cycles frame [ADDR]
+                       addresses can be symbols, e.g. `break init` or `[lives+1] == 0`
+breakpoints            list breakpoints
+delete [ID]            delete one or all breakpoints
+continue, c            run until a breakpoint
+step, s [N]            execute N instructions
+next, n                step over JSR
+finish                 run until the current subroutine returns
+backtrace, bt          show the calls and interrupts PC is nested in and stack tricks seen
+regs, r                show registers and flags
+set REG VALUE          set a, x, y, sp, pc, p or a flag (n v b d i z c)
+mem, m ADDR [LEN]      dump memory
+poke ADDR BYTE...      write memory
+asm, a ADDR INSTR[; INSTR...]
+                       assemble instructions into memory, e.g. `asm $0300 LDA #1; RTS`
+watch, w ADDR[-END] [rwx] [if value OP BYTE]
+                       stop on reads, writes (the default) or execution in a range
+watchpoints            list watchpoints
+unwatch [ID]           delete one or all watchpoints
+disasm, d [ADDR] [N]   disassemble N instructions, around PC by default
+symbols, sym [TEXT]    list loaded symbols, or those with TEXT in their name
+reset, power           soft reset or power cycle
+quit, q                stop emulation
+An empty line repeats the last command";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+    A,
+    X,
+    Y,
+    SP,
+    PC,
+    P,
+    Cycles,
+    Frame,
+    Mem(u16),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// Two character operators first, so `<=` isn't read as `<`
+const COMPARE_SYMBOLS: [(&str, Compare); 6] = [
+    ("==", Compare::Eq), ("!=", Compare::Ne), ("<=", Compare::Le),
+    (">=", Compare::Ge), ("<", Compare::Lt), (">", Compare::Gt),
+];
+
+impl Compare {
+    // Splits `lhs op rhs` at the operator
+    pub fn split(text: &str) -> Option<(&str, Compare, &str)> {
+        COMPARE_SYMBOLS.iter().find_map(|(symbol, compare)| {
+            text.split_once(symbol).map(|(lhs, rhs)| (lhs, *compare, rhs))
+        })
+    }
+
+    pub fn holds(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            Compare::Eq => lhs == rhs,
+            Compare::Ne => lhs != rhs,
+            Compare::Lt => lhs < rhs,
+            Compare::Le => lhs <= rhs,
+            Compare::Gt => lhs > rhs,
+            Compare::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl std::fmt::Display for Compare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (symbol, _) = COMPARE_SYMBOLS.iter().find(|(_, compare)| compare == self).unwrap();
+        write!(f, "{}", symbol)
+    }
+}
+
+// `operand op value`, e.g. `x >= $10` or `[$00F0] == 1`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Condition {
+    pub operand: Operand,
+    pub compare: Compare,
+    pub value: u64,
+}
+
+// A number, a symbol or `symbol+offset`
+fn parse_value(bus: &Bus, text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (name, offset) = match text.split_once('+') {
+        Some((name, offset)) => (name.trim(), parse_number(offset)?),
+        None => (text, 0),
+    };
+
+    match bus.symbols().get(name) {
+        Some(symbol) => {
+            let addr = bus.symbol_addr(symbol).ok_or(format!("{} is in PRG bank {}, which isn't mapped", name, symbol.bank().unwrap_or(0)))?;
+            Ok(addr as u64 + offset)
+        }
+        None if offset != 0 => Err(format!("Unknown symbol {}", name)),
+        None => parse_number(text),
+    }
+}
+
+fn parse_addr(bus: &Bus, text: &str) -> Result<u16, String> {
+    let addr = parse_value(bus, text)?;
+    u16::try_from(addr).map_err(|_| format!("Address {} is out of range", text))
+}
+
+fn parse_operand(bus: &Bus, text: &str) -> Result<Operand, String> {
+    if let Some(addr) = text.strip_prefix('[').and_then(|text| text.strip_suffix(']')) {
+        return Ok(Operand::Mem(parse_addr(bus, addr)?));
+    }
+
+    match text.to_lowercase().as_str() {
+        "a" => Ok(Operand::A),
+        "x" => Ok(Operand::X),
+        "y" => Ok(Operand::Y),
+        "sp" | "s" => Ok(Operand::SP),
+        "pc" => Ok(Operand::PC),
+        "p" => Ok(Operand::P),
+        "cycles" | "cyc" => Ok(Operand::Cycles),
+        "frame" => Ok(Operand::Frame),
+        _ => Err(format!("Unknown operand {}", text)),
+    }
+}
+
+impl Condition {
+    // Addresses and values can name symbols loaded on `bus`
+    pub fn parse(text: &str, bus: &Bus) -> Result<Condition, String> {
+        let (operand, compare, value) = Compare::split(text).ok_or(format!("Expected a comparison, got {}", text))?;
+
+        Ok(Condition {
+            operand: parse_operand(bus, operand.trim())?,
+            compare,
+            value: parse_value(bus, value)?,
+        })
+    }
+
+    // Several conditions joined by &&
+    pub fn parse_all(text: &str, bus: &Bus) -> Result<Vec<Condition>, String> {
+        text.split("&&").map(|condition| Condition::parse(condition, bus)).collect()
+    }
+
+    pub fn holds(&self, nes: &Nes) -> bool {
+        let cpu = nes.cpu();
+        let value = match self.operand {
+            Operand::A => cpu.register_a as u64,
+            Operand::X => cpu.register_x as u64,
+            Operand::Y => cpu.register_y as u64,
+            Operand::SP => cpu.stack_pointer as u64,
+            Operand::PC => cpu.program_counter as u64,
+            Operand::P => cpu.status.bits() as u64,
+            Operand::Cycles => nes.bus().cycles() as u64,
+            Operand::Frame => nes.frame_count() as u64,
+            Operand::Mem(addr) => nes.bus().peek(addr) as u64,
+        };
+
+        self.compare.holds(value, self.value)
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.operand {
+            Operand::Mem(addr) => write!(f, "[${:04X}]", addr)?,
+            Operand::Cycles => write!(f, "cycles")?,
+            Operand::Frame => write!(f, "frame")?,
+            operand => f.write_str(&format!("{:?}", operand).to_lowercase())?,
+        }
+        write!(f, " {} ${:X}", self.compare, self.value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: Option<u16>,
+    // 16K PRG bank that has to be mapped at `addr`, for labels of banked code
+    pub bank: Option<usize>,
+    pub conditions: Vec<Condition>,
+    // Breakpoints without an address fire when their condition becomes true,
+    // not on every instruction while it stays true
+    was_true: bool,
+}
+
+impl Breakpoint {
+    fn describe(&self) -> String {
+        let mut text = format!("#{}", self.id);
+        if let Some(addr) = self.addr {
+            write!(text, " at ${:04X}", addr).unwrap();
+        }
+        if let Some(bank) = self.bank {
+            write!(text, " in bank {}", bank).unwrap();
+        }
+        if !self.conditions.is_empty() {
+            let conditions: Vec<String> = self.conditions.iter().map(Condition::to_string).collect();
+            write!(text, " if {}", conditions.join(" && ")).unwrap();
+        }
+        text
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Resume {
+    Paused,
+    Continue,
+    Steps(usize),
+    // Until PC comes back to `pc` with the stack no deeper than `sp`
+    StepOver { pc: u16, sp: u8 },
+    // Until the stack unwinds past `sp`, i.e. the subroutine returned
+    Finish { sp: u8 },
+}
+
+pub enum Flow {
+    // Wait for the next command
+    Prompt,
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    resume: Resume,
+    // Set when resuming, so the instruction we stopped at runs first
+    resuming: bool,
+    // Address of the instruction that ran last, which caused any watch hit
+    last_pc: u16,
+    // Why the last stop happened, shown by the REPL
+    reason: Option<String>,
+    last_command: String,
+    call_stack: CallStack,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    // Starts paused, so the first should_break returns true
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: vec![],
+            next_id: 1,
+            resume: Resume::Paused,
+            resuming: false,
+            last_pc: 0,
+            reason: None,
+            last_command: String::new(),
+            call_stack: CallStack::new(),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.resume = Resume::Paused;
+        self.resuming = false;
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, addr: Option<u16>, conditions: Vec<Condition>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, addr, bank: None, conditions, was_true: false });
+        id
+    }
+
+    fn check_breakpoints(&mut self, nes: &Nes) -> Option<String> {
+        let pc = nes.cpu().program_counter;
+        let mut hit = None;
+
+        for breakpoint in &mut self.breakpoints {
+            let holds = breakpoint.conditions.iter().all(|condition| condition.holds(nes));
+
+            let fired = match breakpoint.addr {
+                Some(addr) => {
+                    let bank = nes.bus().prg_offset(pc).map(|offset| offset / PRG_BANK_SIZE);
+                    addr == pc && breakpoint.bank.is_none_or(|expected| bank == Some(expected)) && holds
+                }
+                None => holds && !breakpoint.was_true,
+            };
+            breakpoint.was_true = holds;
+
+            if fired && hit.is_none() {
+                hit = Some(format!("Breakpoint {}", breakpoint.describe()));
+            }
+        }
+
+        hit
+    }
+
+    // Call before every instruction, returns true when the debugger wants
+    // the user to take over
+    pub fn should_break(&mut self, nes: &Nes) -> bool {
+        let cpu = nes.cpu();
+        let watchpoints = nes.bus().watchpoints();
+        let pc = cpu.program_counter;
+        self.call_stack.update(cpu);
+
+        if std::mem::take(&mut self.resuming) {
+            // Drop hits from the reads done while paused, and keep edge
+            // triggered breakpoints up to date
+            watchpoints.take_hit();
+            self.check_breakpoints(nes);
+            watchpoints.take_hit();
+            self.last_pc = pc;
+            return false;
+        }
+
+        // Reads and writes happened in the instruction that just ran,
+        // execute watchpoints fire before the instruction at PC
+        let mut watch_hit = watchpoints.take_hit().map(|hit| (hit, self.last_pc));
+        if watch_hit.is_none() {
+            nes.bus().watch_execute(pc);
+            watch_hit = watchpoints.take_hit().map(|hit| (hit, pc));
+        }
+
+        let stop = match &mut self.resume {
+            Resume::Paused => true,
+            Resume::Continue => false,
+            Resume::Steps(steps) => {
+                *steps -= 1;
+                *steps == 0
+            }
+            Resume::StepOver { pc, sp } => cpu.program_counter == *pc && cpu.stack_pointer >= *sp,
+            Resume::Finish { sp } => cpu.stack_pointer > *sp,
+        };
+
+        let hit = match watch_hit {
+            Some((hit, by)) => Some(describe_watch_hit(cpu, hit, by)),
+            None => self.check_breakpoints(nes),
+        };
+        watchpoints.take_hit();
+        self.last_pc = pc;
+
+        if stop || hit.is_some() {
+            self.reason = hit;
+            self.resume = Resume::Paused;
+            return true;
+        }
+        false
+    }
+
+    fn resume(&mut self, resume: Resume) -> Flow {
+        self.resume = resume;
+        self.resuming = true;
+        self.reason = None;
+        Flow::Resume
+    }
+
+    // Runs one command line, returns what it printed and whether to resume
+    pub fn execute(&mut self, nes: &mut Nes, line: &str) -> (String, Flow) {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+
+        match self.run_command(nes, &line) {
+            Ok(result) => result,
+            Err(err) => (err, Flow::Prompt),
+        }
+    }
+
+    fn run_command(&mut self, nes: &mut Nes, line: &str) -> Result<(String, Flow), String> {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        let cpu = nes.cpu();
+
+        let output = match command {
+            "" => String::new(),
+            "help" | "h" | "?" => HELP.to_string(),
+            "continue" | "c" => return Ok((String::new(), self.resume(Resume::Continue))),
+            "step" | "s" => {
+                let steps = args.first().map(|n| parse_number(n)).transpose()?.unwrap_or(1).max(1);
+                return Ok((String::new(), self.resume(Resume::Steps(steps as usize))));
+            }
+            "next" | "n" => {
+                let resume = if cpu.bus.peek(cpu.program_counter) == JSR {
+                    Resume::StepOver { pc: cpu.program_counter.wrapping_add(3), sp: cpu.stack_pointer }
+                } else {
+                    Resume::Steps(1)
+                };
+                return Ok((String::new(), self.resume(resume)));
+            }
+            "finish" => return Ok((String::new(), self.resume(Resume::Finish { sp: cpu.stack_pointer }))),
+            "backtrace" | "bt" => self.call_stack.backtrace(cpu),
+            "quit" | "q" => return Ok((String::new(), Flow::Quit)),
+            "break" | "b" => self.break_command(nes.bus(), rest)?,
+            "breakpoints" | "bl" => {
+                let list: Vec<String> = self.breakpoints.iter().map(Breakpoint::describe).collect();
+                if list.is_empty() { "No breakpoints".to_string() } else { list.join("\n") }
+            }
+            "delete" => match args.first() {
+                Some(id) => {
+                    let id = parse_number(id)? as usize;
+                    let len = self.breakpoints.len();
+                    self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+                    if self.breakpoints.len() == len {
+                        return Err(format!("No breakpoint {}", id));
+                    }
+                    format!("Deleted breakpoint {}", id)
+                }
+                None => {
+                    self.breakpoints.clear();
+                    "Deleted all breakpoints".to_string()
+                }
+            },
+            "watch" | "w" => self.watch_command(nes, rest)?,
+            "watchpoints" | "wl" => {
+                let list: Vec<String> = nes.bus().watchpoints().list().iter().map(|w| w.to_string()).collect();
+                if list.is_empty() { "No watchpoints".to_string() } else { list.join("\n") }
+            }
+            "unwatch" => match args.first() {
+                Some(id) => {
+                    let id = parse_number(id)? as usize;
+                    if !nes.bus_mut().watchpoints_mut().remove(id) {
+                        return Err(format!("No watchpoint {}", id));
+                    }
+                    format!("Deleted watchpoint {}", id)
+                }
+                None => {
+                    nes.bus_mut().watchpoints_mut().clear();
+                    "Deleted all watchpoints".to_string()
+                }
+            },
+            "regs" | "r" => registers(nes),
+            "set" => {
+                let [register, value] = args[..] else {
+                    return Err("Usage: set REG VALUE".to_string());
+                };
+                set_register(nes.cpu_mut(), register, parse_number(value)?)?;
+                registers(nes)
+            }
+            "mem" | "m" => {
+                let addr = parse_addr(nes.bus(), args.first().ok_or("Usage: mem ADDR [LEN]")?)?;
+                let len = args.get(1).map(|len| parse_number(len)).transpose()?.unwrap_or(64);
+                dump(cpu, addr, len as usize)
+            }
+            "poke" => {
+                let addr = parse_addr(nes.bus(), args.first().ok_or("Usage: poke ADDR BYTE...")?)?;
+                let bytes = args[1..].iter()
+                    .map(|byte| u8::try_from(parse_number(byte)?).map_err(|_| format!("{} isn't a byte", byte)))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                write_memory(nes, addr, &bytes)?;
+                dump(nes.cpu(), addr, bytes.len())
+            }
+            "asm" | "a" => {
+                let (addr, source) = rest.trim().split_once(' ').ok_or("Usage: asm ADDR INSTRUCTION[; INSTRUCTION...]")?;
+                let addr = parse_addr(nes.bus(), addr)?;
+                let mut assembler = Assembler::new(addr);
+                for symbol in nes.bus().symbols().list() {
+                    if let Some(value) = nes.bus().symbol_addr(symbol) {
+                        assembler.define(&symbol.name, value);
+                    }
+                }
+                let bytes = assembler.assemble(&source.replace(';', "\n")).map_err(|err| err.to_string())?;
+                write_memory(nes, addr, &bytes)?;
+                disassemble_range(nes.cpu(), addr, bytes.len())
+            }
+            "disasm" | "d" => {
+                let addr = args.first().map(|addr| parse_addr(nes.bus(), addr)).transpose()?;
+                let count = args.get(1).map(|n| parse_number(n)).transpose()?.unwrap_or(10);
+                disassemble(cpu, addr, count as usize)
+            }
+            "symbols" | "sym" => list_symbols(nes.bus(), args.first().copied().unwrap_or("")),
+            "reset" => {
+                nes.reset();
+                format!("Reset, PC ${:04X}", nes.cpu().program_counter)
+            }
+            "power" => {
+                nes.power_on();
+                format!("Power cycled, PC ${:04X}", nes.cpu().program_counter)
+            }
+            _ => return Err(format!("Unknown command {}, try help", command)),
+        };
+
+        Ok((output, Flow::Prompt))
+    }
+
+    fn break_command(&mut self, bus: &Bus, rest: &str) -> Result<String, String> {
+        let rest = rest.trim();
+        let (addr, conditions) = match rest.strip_prefix("if ") {
+            Some(conditions) => (None, conditions),
+            None => match rest.split_once(" if ") {
+                Some((addr, conditions)) => (Some(addr), conditions),
+                None => (Some(rest), ""),
+            },
+        };
+
+        let (addr, bank) = match addr {
+            Some("") => return Err("Usage: break ADDR [if COND] or break if COND".to_string()),
+            // A label of banked code only fires while its bank is mapped
+            Some(addr) => (Some(parse_addr(bus, addr)?), bus.symbols().get(addr).and_then(|symbol| symbol.bank())),
+            None => (None, None),
+        };
+        let conditions = if conditions.is_empty() { vec![] } else { Condition::parse_all(conditions, bus)? };
+
+        let id = self.add_breakpoint(addr, conditions);
+        let breakpoint = self.breakpoints.iter_mut().find(|b| b.id == id).unwrap();
+        breakpoint.bank = bank;
+        Ok(format!("Breakpoint {}", breakpoint.describe()))
+    }
+
+    fn watch_command(&mut self, nes: &mut Nes, rest: &str) -> Result<String, String> {
+        let usage = "Usage: watch ADDR[-END] [rwx] [if value OP BYTE]";
+        let (watch, condition) = match rest.split_once(" if ") {
+            Some((watch, condition)) => (watch, Some(condition)),
+            None => (rest, None),
+        };
+
+        let args: Vec<&str> = watch.split_whitespace().collect();
+        let (start, end) = parse_range(nes.bus(), args.first().ok_or(usage)?)?;
+        let access = match args.get(1) {
+            Some(access) => Access::parse(access)?,
+            None => Access::WRITE,
+        };
+        if access.is_empty() || args.len() > 2 {
+            return Err(usage.to_string());
+        }
+
+        let value = match condition {
+            Some(condition) => {
+                let (operand, compare, value) = Compare::split(condition).ok_or(usage)?;
+                if operand.trim() != "value" {
+                    return Err(usage.to_string());
+                }
+                let value = u8::try_from(parse_number(value)?).map_err(|_| format!("{} isn't a byte", value.trim()))?;
+                Some((compare, value))
+            }
+            None => None,
+        };
+
+        let watchpoints = nes.bus_mut().watchpoints_mut();
+        let id = watchpoints.add(start, end, access, value);
+        Ok(format!("Watchpoint {}", watchpoints.list().iter().find(|w| w.id == id).unwrap()))
+    }
+
+    // Shows why execution stopped and reads commands until one resumes.
+    // Returns false on quit or end of input
+    pub fn repl<R: BufRead, W: Write>(&mut self, nes: &mut Nes, input: &mut R, out: &mut W) -> bool {
+        if let Some(reason) = self.reason.take() {
+            writeln!(out, "{}", reason).unwrap();
+        }
+        writeln!(out, "{}", tracer::format_line(nes, TraceFormat::Nestest)).unwrap();
+
+        loop {
+            write!(out, "(famemu) ").unwrap();
+            out.flush().unwrap();
+
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                return false;
+            }
+
+            let (output, flow) = self.execute(nes, &line);
+            if !output.is_empty() {
+                writeln!(out, "{}", output).unwrap();
+            }
+
+            match flow {
+                Flow::Prompt => {}
+                Flow::Resume => return true,
+                Flow::Quit => return false,
+            }
+        }
+    }
+}
+
+fn describe_watch_hit(cpu: &CPU, hit: WatchHit, pc: u16) -> String {
+    let access = if hit.access == Access::READ {
+        format!("read ${:02X} from ${:04X}", hit.value, hit.addr)
+    } else if hit.access == Access::WRITE {
+        format!("write ${:02X} to ${:04X}", hit.value, hit.addr)
+    } else {
+        format!("execute ${:04X}", hit.addr)
+    };
+
+    format!("Watchpoint #{}: {} by {:04X}  {}", hit.id, access, pc, disassemble_labelled(cpu, pc))
+}
+
+// The bus can't write cartridge ROM, so patches have to go to RAM
+fn write_memory(nes: &mut Nes, addr: u16, bytes: &[u8]) -> Result<(), String> {
+    if addr as usize + bytes.len() > CARTRIDGE_ROM as usize {
+        return Err(format!("Can't write ${:04X}-${:04X}, ROM starts at ${:04X}", addr, addr as usize + bytes.len() - 1, CARTRIDGE_ROM));
+    }
+
+    for (i, byte) in bytes.iter().enumerate() {
+        nes.cpu_mut().mem_write(addr + i as u16, *byte);
+    }
+    Ok(())
+}
+
+// `$0200`, `$0200-$02FF`, or a symbol, which covers the whole array it labels
+fn parse_range(bus: &Bus, text: &str) -> Result<(u16, u16), String> {
+    if let Some((start, end)) = text.split_once('-') {
+        return Ok((parse_addr(bus, start)?, parse_addr(bus, end)?));
+    }
+
+    let start = parse_addr(bus, text)?;
+    let size = bus.symbols().get(text).map_or(1, |symbol| symbol.size);
+    Ok((start, start.saturating_add(size.max(1) - 1)))
+}
+
+fn list_symbols(bus: &Bus, filter: &str) -> String {
+    let mut lines: Vec<(Option<u16>, String)> = bus.symbols().list().iter()
+        .filter(|symbol| symbol.name.contains(filter))
+        .map(|symbol| {
+            let addr = bus.symbol_addr(symbol);
+            let mut line = match addr {
+                Some(addr) => format!("${:04X}  {}", addr, symbol.name),
+                None => format!("       {}", symbol.name),
+            };
+            if symbol.size > 1 {
+                write!(line, " ({} bytes)", symbol.size).unwrap();
+            }
+            if let Some(bank) = symbol.bank() {
+                write!(line, " in bank {}", bank).unwrap();
+            }
+            (addr, line)
+        })
+        .collect();
+    lines.sort();
+
+    if lines.is_empty() {
+        return "No symbols".to_string();
+    }
+    lines.into_iter().map(|(_, line)| line).collect::<Vec<String>>().join("\n")
+}
+
+// ca65 syntax, with operands named by their labels
+fn disassemble_labelled(cpu: &CPU, addr: u16) -> String {
+    decode_at(&cpu.bus, addr).format(|addr| cpu.bus.label(addr).map(str::to_string), false)
+}
+
+pub fn registers(nes: &Nes) -> String {
+    let cpu = nes.cpu();
+    let flags: String = "NV-BDIZC".chars().enumerate()
+        .map(|(i, c)| if cpu.status.bits() & (0x80 >> i) != 0 { c } else { c.to_ascii_lowercase() })
+        .collect();
+
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} PC:{:04X} P:{:02X} {} CYC:{} FRAME:{}",
+        cpu.register_a, cpu.register_x, cpu.register_y, cpu.stack_pointer, cpu.program_counter,
+        cpu.status.bits(), flags, nes.bus().cycles(), nes.frame_count()
+    )
+}
+
+fn set_register(cpu: &mut CPU, register: &str, value: u64) -> Result<(), String> {
+    let byte = || u8::try_from(value).map_err(|_| format!("{} doesn't fit in a byte", value));
+
+    let flag = match register.to_lowercase().as_str() {
+        "a" => return byte().map(|value| cpu.register_a = value),
+        "x" => return byte().map(|value| cpu.register_x = value),
+        "y" => return byte().map(|value| cpu.register_y = value),
+        "sp" | "s" => return byte().map(|value| cpu.stack_pointer = value),
+        "p" => return byte().map(|value| cpu.status = CpuFlags::from_bits_truncate(value)),
+        "pc" => {
+            cpu.program_counter = u16::try_from(value).map_err(|_| format!("{} doesn't fit in 16 bits", value))?;
+            return Ok(());
+        }
+        "n" => CpuFlags::NEGATIVE,
+        "v" => CpuFlags::OVERFLOW,
+        "b" => CpuFlags::BREAK,
+        "d" => CpuFlags::DECIMAL_MODE,
+        "i" => CpuFlags::INTERRUPT_DISABLE,
+        "z" => CpuFlags::ZERO,
+        "c" => CpuFlags::CARRY,
+        _ => return Err(format!("Unknown register {}", register)),
+    };
+
+    cpu.status.set(flag, value != 0);
+    Ok(())
+}
+
+fn dump(cpu: &CPU, addr: u16, len: usize) -> String {
+    let mut text = String::new();
+
+    for row in (0..len).step_by(16) {
+        let start = addr.wrapping_add(row as u16);
+        let bytes: Vec<String> = (0..(len - row).min(16))
+            .map(|i| format!("{:02X}", cpu.bus.peek(start.wrapping_add(i as u16))))
+            .collect();
+        writeln!(text, "{:04X}: {}", start, bytes.join(" ")).unwrap();
+    }
+
+    text.trim_end().to_string()
+}
+
+// Instruction boundaries before PC aren't known, so try start points a few
+// bytes back and take the furthest one that decodes right into PC
+fn start_before(cpu: &CPU, pc: u16, instructions: usize) -> u16 {
+    for back in (1..=instructions as u16 * 3).rev() {
+        let mut addr = pc.wrapping_sub(back);
+        let mut count = 0;
+
+        while addr != pc && pc.wrapping_sub(addr) <= back {
+            addr = addr.wrapping_add(disassemble_at(&cpu.bus, addr).1);
+            count += 1;
+        }
+
+        if addr == pc && count <= instructions {
+            return pc.wrapping_sub(back);
+        }
+    }
+
+    pc
+}
+
+fn disassemble(cpu: &CPU, addr: Option<u16>, count: usize) -> String {
+    let pc = cpu.program_counter;
+    let mut addr = addr.unwrap_or_else(|| start_before(cpu, pc, count / 3));
+    let mut text = String::new();
+
+    for _ in 0..count {
+        if let Some(label) = cpu.bus.label(addr) {
+            writeln!(text, "{}:", label).unwrap();
+        }
+        let marker = if addr == pc { '>' } else { ' ' };
+        writeln!(text, "{} {:04X}  {}", marker, addr, disassemble_labelled(cpu, addr)).unwrap();
+        addr = addr.wrapping_add(disassemble_at(&cpu.bus, addr).1);
+    }
+
+    text.trim_end().to_string()
+}
+
+// Every instruction in the `len` bytes from `addr`
+fn disassemble_range(cpu: &CPU, addr: u16, len: usize) -> String {
+    let (mut offset, mut count) = (0, 0);
+    while offset < len {
+        offset += disassemble_at(&cpu.bus, addr.wrapping_add(offset as u16)).1 as usize;
+        count += 1;
+    }
+
+    disassemble(cpu, Some(addr), count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::joypad::JoypadButtons;
+    use crate::nes::test_util::nestest;
+    use crate::symbols::Symbols;
+
+    // $0600: JSR $0610, LDA #$01, BRK   $0610: INX, INX, RTS
+    fn program() -> Nes {
+        let mut nes = nestest();
+
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut nes, "asm $0600 JSR $0610; LDA #$01; BRK");
+        debugger.execute(&mut nes, "asm $0610 INX; INX; RTS");
+        nes.cpu_mut().program_counter = 0x0600;
+        nes
+    }
+
+    fn run(debugger: &mut Debugger, nes: &mut Nes, command: &str) {
+        let (_, flow) = debugger.execute(nes, command);
+        assert!(matches!(flow, Flow::Resume));
+
+        while !debugger.should_break(nes) && nes.step() {}
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut nes = program();
+        let mut debugger = Debugger::new();
+        assert!(debugger.should_break(&nes));
+
+        run(&mut debugger, &mut nes, "next");
+        assert_eq!((nes.cpu().program_counter, nes.cpu().register_x), (0x0603, 2));
+
+        nes.cpu_mut().program_counter = 0x0600;
+        run(&mut debugger, &mut nes, "step");
+        assert_eq!(nes.cpu().program_counter, 0x0610);
+        run(&mut debugger, &mut nes, "finish");
+        assert_eq!((nes.cpu().program_counter, nes.cpu().register_x), (0x0603, 4));
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut nes = program();
+        let mut debugger = Debugger::new();
+        debugger.should_break(&nes);
+
+        debugger.execute(&mut nes, "break if x == 1");
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(nes.cpu().program_counter, 0x0611);
+
+        debugger.execute(&mut nes, "delete");
+        debugger.execute(&mut nes, "break $0605 if a == 1 && [$0610] == $E8");
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(nes.cpu().program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut nes = program();
+        let mut debugger = Debugger::new();
+        debugger.should_break(&nes);
+
+        // JSR pushes the return address $0602 onto the stack
+        debugger.execute(&mut nes, "watch $01F0-$01FF w if value == 6");
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(nes.cpu().program_counter, 0x0610);
+        assert_eq!(debugger.reason.as_deref(), Some("Watchpoint #1: write $06 to $01FD by 0600  JSR $0610"));
+
+        // Looking at memory isn't a read
+        debugger.execute(&mut nes, "unwatch");
+        debugger.execute(&mut nes, "watch $0610 r");
+        debugger.execute(&mut nes, "mem $0610 4");
+        debugger.execute(&mut nes, "disasm $0610 2");
+        assert_eq!(nes.bus().watchpoints().take_hit(), None);
+
+        debugger.execute(&mut nes, "unwatch");
+        debugger.execute(&mut nes, "watch $0611 x");
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(nes.cpu().program_counter, 0x0611);
+    }
+
+    #[test]
+    fn test_prompt_doesnt_read_controllers() {
+        let mut nes = program();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut nes, "asm $0600 LDA #1; STA $4016; LDA #0; STA $4016; LDA $4016");
+        nes.set_input(0, JoypadButtons::A);
+        for _ in 0..4 {
+            nes.step();
+        }
+
+        let mut out = vec![];
+        assert!(debugger.repl(&mut nes, &mut "c\n".as_bytes(), &mut out));
+        assert!(String::from_utf8(out).unwrap().starts_with("060A  AD 16 40  LDA $4016 = 01"));
+        nes.step();
+        assert_eq!(nes.cpu().register_a, 1);
+    }
+
+    #[test]
+    fn test_inspect_and_edit() {
+        let mut nes = program();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut nes, "set a $42");
+        debugger.execute(&mut nes, "set c 1");
+        assert_eq!(nes.cpu().register_a, 0x42);
+        assert!(nes.cpu().status.contains(CpuFlags::CARRY));
+
+        assert_eq!(debugger.execute(&mut nes, "poke $0700 1 2 $FF").0, "0700: 01 02 FF");
+        assert_eq!(debugger.execute(&mut nes, "mem $0700 2").0, "0700: 01 02");
+        assert!(debugger.execute(&mut nes, "poke $8000 1").0.starts_with("Can't write"));
+
+        let (disasm, _) = debugger.execute(&mut nes, "disasm $0600 3");
+        assert_eq!(disasm, "> 0600  JSR $0610\n  0603  LDA #$01\n  0605  BRK");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut nes = program();
+        let mut symbols = Symbols::default();
+        symbols.load_nl("$0610#double#\n$0700/2#counter#\n", None).unwrap();
+        nes.bus_mut().set_symbols(symbols);
+        let mut debugger = Debugger::new();
+        debugger.should_break(&nes);
+
+        assert_eq!(debugger.execute(&mut nes, "break double").0, "Breakpoint #1 at $0610");
+        run(&mut debugger, &mut nes, "c");
+        assert_eq!(nes.cpu().program_counter, 0x0610);
+
+        let (disasm, _) = debugger.execute(&mut nes, "disasm $0600 2");
+        assert_eq!(disasm, "  0600  JSR double\n  0603  LDA #$01");
+        assert_eq!(debugger.execute(&mut nes, "watch counter").0, "Watchpoint #1 w $0700-$0701");
+        assert_eq!(debugger.execute(&mut nes, "asm $0620 JMP double").0, "  0620  JMP double");
+        assert_eq!(debugger.execute(&mut nes, "break if [counter+1] == 1").0, "Breakpoint #2 if [$0701] == $1");
+    }
+}
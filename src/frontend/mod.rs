@@ -0,0 +1,225 @@
+use crate::cli::RunArgs;
+use crate::{log_trace, FileTracer, MovieSession};
+use famemu::bus::battery::BatterySave;
+use famemu::rewind::Rewind;
+use famemu::screen::{self, Frame};
+use famemu::savestate;
+use famemu::{JoypadButtons, Nes};
+
+use std::path::Path;
+use std::time::Duration;
+
+use sdl2::event::Event;
+use sdl2::EventPump;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureAccess};
+use sdl2::video::Window;
+
+// How long one rewound frame stays on screen
+const REWIND_FRAME_TIME: Duration = Duration::from_millis(16);
+
+struct Hotkeys {
+    quit: bool,
+    rewinding: bool,
+    state_slot: u8,
+    buttons: JoypadButtons,
+    // Soft (F2) or hard (F3) reset to do before the next frame
+    reset: Option<bool>,
+    toggle_trace: bool,
+}
+
+fn button(keycode: Keycode) -> Option<JoypadButtons> {
+    match keycode {
+        Keycode::W | Keycode::Up => Some(JoypadButtons::UP),
+        Keycode::A | Keycode::Left => Some(JoypadButtons::LEFT),
+        Keycode::S | Keycode::Down => Some(JoypadButtons::DOWN),
+        Keycode::D | Keycode::Right => Some(JoypadButtons::RIGHT),
+        Keycode::X => Some(JoypadButtons::A),
+        Keycode::Z => Some(JoypadButtons::B),
+        Keycode::Return => Some(JoypadButtons::START),
+        Keycode::RShift => Some(JoypadButtons::SELECT),
+        _ => None,
+    }
+}
+
+fn slot(keycode: Keycode) -> Option<u8> {
+    let slots = [
+        Keycode::Num0, Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+        Keycode::Num5, Keycode::Num6, Keycode::Num7, Keycode::Num8, Keycode::Num9,
+    ];
+
+    slots.iter().position(|key| *key == keycode).map(|slot| slot as u8)
+}
+
+// Keys are mapped to controller 1. 0-9 pick the save state slot, F5 saves and
+// F7 loads it, holding Backspace rewinds, F2 resets, F3 power cycles and F9
+// turns tracing on and off. Save states are off while a movie is recording or
+// playing, like rewinding
+fn handle_user_input(nes: &mut Nes, event_pump: &mut EventPump, rom_path: &Path, hotkeys: &mut Hotkeys, movie_active: bool) {
+    for event in event_pump.poll_iter() {
+        match event {
+            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                hotkeys.quit = true;
+                return;
+            },
+            Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                hotkeys.rewinding = true;
+            },
+            Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => {
+                hotkeys.rewinding = false;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F2), .. } => {
+                hotkeys.reset = Some(false);
+            },
+            Event::KeyDown { keycode: Some(Keycode::F3), .. } => {
+                hotkeys.reset = Some(true);
+            },
+            Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                hotkeys.toggle_trace = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F5 | Keycode::F7), .. } if movie_active => {
+                println!("Save states are off during a movie");
+            },
+            Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                match savestate::save_slot(nes, rom_path, hotkeys.state_slot) {
+                    Ok(path) => println!("Saved state to {}", path.display()),
+                    Err(err) => println!("Failed to save state: {}", err),
+                }
+            },
+            Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                match savestate::load_slot(nes, rom_path, hotkeys.state_slot) {
+                    Ok(path) => println!("Loaded state from {}", path.display()),
+                    Err(err) => println!("Failed to load state: {}", err),
+                }
+            },
+            Event::KeyDown { keycode: Some(keycode), .. } if slot(keycode).is_some() => {
+                hotkeys.state_slot = slot(keycode).unwrap();
+                println!("Save state slot {}", hotkeys.state_slot);
+            },
+            Event::KeyDown { keycode: Some(keycode), .. } => {
+                hotkeys.buttons |= button(keycode).unwrap_or_default();
+            },
+            Event::KeyUp { keycode: Some(keycode), .. } => {
+                hotkeys.buttons -= button(keycode).unwrap_or_default();
+            }
+
+            _ => { /* DO NOTHING */}
+        }
+    }
+}
+
+fn draw(canvas: &mut Canvas<Window>, texture: &mut Texture, frame: &Frame) {
+    texture.update(None, frame, screen::WIDTH * 3).unwrap();
+    canvas.copy(texture, None, None).unwrap();
+    canvas.present();
+}
+
+pub fn run_window(
+    nes: &mut Nes, args: &RunArgs, title: &str, battery: &mut Option<BatterySave>, movie: &mut MovieSession,
+    tracer: &mut Option<FileTracer>
+) {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let window = video_subsystem
+            .window(title, screen::WIDTH as u32 * args.scale, screen::HEIGHT as u32 * args.scale)
+            .position_centered()
+            .build().unwrap();
+
+    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    canvas.set_scale(args.scale as f32, args.scale as f32).unwrap();
+
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture(PixelFormatEnum::RGB24, TextureAccess::Static, screen::WIDTH as u32, screen::HEIGHT as u32).unwrap();
+
+    let mut screen_state = [0u8; screen::FRAME_SIZE];
+    let mut hotkeys = Hotkeys {
+        quit: false, rewinding: false, state_slot: 0, buttons: JoypadButtons::empty(), reset: None, toggle_trace: false
+    };
+    let mut rewind = Rewind::new(args.rewind_interval, args.rewind_budget << 20);
+    let mut halt_reported = false;
+
+    while !hotkeys.quit && args.emulation.frames.is_none_or(|limit| nes.frame_count() < limit) {
+        // The window stays open after BRK so the run can still be rewound
+        if hotkeys.rewinding || nes.is_halted() {
+            if nes.is_halted() && !halt_reported {
+                println!("CPU halted, hold Backspace to rewind or Esc to quit");
+                halt_reported = true;
+            }
+
+            handle_user_input(nes, &mut event_pump, &args.rom.rom, &mut hotkeys, movie.is_active());
+            // Rewinding would cut the movie short
+            if hotkeys.rewinding && !movie.is_active() && rewind.step_back(nes) {
+                screen_state = *nes.frame();
+                draw(&mut canvas, &mut texture, &screen_state);
+                halt_reported = false;
+            }
+
+            std::thread::sleep(REWIND_FRAME_TIME);
+            continue;
+        }
+
+        if let Some(hard) = hotkeys.reset.take() {
+            movie.reset(nes, hard);
+        }
+
+        // A movie needs the controllers to change on frame boundaries only
+        if movie.is_playing() {
+            hotkeys.buttons = nes.bus().buttons(0);
+        } else {
+            nes.set_input(0, hotkeys.buttons);
+        }
+
+        if !movie.begin_frame(nes) {
+            println!("Movie finished");
+            break;
+        }
+
+        let finished = nes.run_frame_with(|nes| {
+            log_trace(tracer, nes);
+
+            handle_user_input(nes, &mut event_pump, &args.rom.rom, &mut hotkeys, movie.is_active());
+            if std::mem::take(&mut hotkeys.toggle_trace) {
+                match tracer.as_mut().map(|tracer| tracer.toggle()) {
+                    Some(on) => println!("Trace {}", if on { "on" } else { "off" }),
+                    None => println!("Start with --trace FILE to trace"),
+                }
+            }
+            if hotkeys.quit || (hotkeys.rewinding && !movie.is_active()) {
+                return false;
+            }
+
+            if !movie.is_active() {
+                nes.set_input(0, hotkeys.buttons);
+            }
+
+            if let Some(battery) = battery.as_mut() {
+                if let Err(err) = battery.tick(nes.bus_mut()) {
+                    println!("Failed to write {}: {}", battery.path().display(), err);
+                }
+            }
+
+            if screen::read_screen_state(nes.bus(), &mut screen_state) {
+                draw(&mut canvas, &mut texture, &screen_state);
+            }
+
+            std::thread::sleep(Duration::new(0, 100_000));
+
+            true
+        });
+
+        if finished {
+            rewind.record(nes);
+
+            if !movie.end_frame(nes) {
+                break;
+            }
+        }
+    }
+
+    if hotkeys.quit {
+        println!("Quit");
+    }
+}
@@ -0,0 +1,451 @@
+use crate::bus::watch::Access;
+use crate::bus::CARTRIDGE_ROM;
+use crate::cpu::cpu::CpuFlags;
+use crate::cpu::mem::Mem;
+use crate::nes::Nes;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+// GDB has no 6502 architecture, so the registers are described from scratch:
+// a, x, y, sp and p are 8 bits, pc 16 bits, in this order in `g` packets
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.famemu.mos6502">
+    <flags id="p_flags" size="1">
+      <field name="C" start="0" end="0"/>
+      <field name="Z" start="1" end="1"/>
+      <field name="I" start="2" end="2"/>
+      <field name="D" start="3" end="3"/>
+      <field name="B" start="4" end="4"/>
+      <field name="V" start="6" end="6"/>
+      <field name="N" start="7" end="7"/>
+    </flags>
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="p_flags"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 6;
+// Advertised in qSupported, memory reads are cut down to fit in it
+const PACKET_SIZE: usize = 0x4000;
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Write, read and access watchpoints, the Z2, Z3 and Z4 packets
+struct Watch {
+    kind: u8,
+    addr: u16,
+    len: u16,
+    id: usize,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// `addr,len` as used by m, M, Z and z
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn registers(nes: &Nes) -> Vec<u8> {
+    let cpu = nes.cpu();
+    let pc = cpu.program_counter.to_le_bytes();
+    vec![cpu.register_a, cpu.register_x, cpu.register_y, cpu.stack_pointer, cpu.status.bits(), pc[0], pc[1]]
+}
+
+fn set_register(nes: &mut Nes, index: usize, value: &[u8]) -> bool {
+    let cpu = nes.cpu_mut();
+    match (index, value) {
+        (0, [a]) => cpu.register_a = *a,
+        (1, [x]) => cpu.register_x = *x,
+        (2, [y]) => cpu.register_y = *y,
+        (3, [sp]) => cpu.stack_pointer = *sp,
+        (4, [p]) => cpu.status = CpuFlags::from_bits_truncate(*p),
+        (5, [low, high]) => cpu.program_counter = u16::from_le_bytes([*low, *high]),
+        _ => return false,
+    }
+    true
+}
+
+// Serves one GDB connection. Execution only happens inside `c` and `s`, the
+// console is stopped the rest of the time
+pub struct GdbStub {
+    stream: TcpStream,
+    ack: bool,
+    breakpoints: Vec<u16>,
+    watches: Vec<Watch>,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> Self {
+        // Packets are tiny, waiting to batch them only adds latency
+        stream.set_nodelay(true).ok();
+        GdbStub { stream, ack: true, breakpoints: vec![], watches: vec![] }
+    }
+
+    // Runs until GDB detaches, kills the target or disconnects. Watchpoints
+    // added by GDB are removed from the bus on the way out
+    pub fn serve(&mut self, nes: &mut Nes) -> io::Result<()> {
+        let result = self.serve_packets(nes);
+
+        for watch in self.watches.drain(..) {
+            nes.bus_mut().watchpoints_mut().remove(watch.id);
+        }
+        result
+    }
+
+    fn serve_packets(&mut self, nes: &mut Nes) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "D" | "D;1" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                "k" | "vKill;1" => return Ok(()),
+                "QStartNoAckMode" => {
+                    self.send("OK")?;
+                    self.ack = false;
+                    continue;
+                }
+                _ if packet.starts_with('c') || packet.starts_with('s') => {
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        nes.cpu_mut().program_counter = addr;
+                    }
+                    self.resume(nes, packet.starts_with('s'))?
+                }
+                _ => self.handle(nes, &packet),
+            };
+
+            self.send(&reply)?;
+        }
+
+        Ok(())
+    }
+
+    // Replies to every packet that doesn't run the CPU. Unsupported ones get
+    // an empty reply, as the protocol asks
+    fn handle(&mut self, nes: &mut Nes, packet: &str) -> String {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => hex(&registers(nes)),
+            "G" => {
+                let Some(bytes) = decode_hex(args).filter(|bytes| bytes.len() == REGISTERS + 1) else {
+                    return "E01".to_string();
+                };
+                let (bytes, pc) = bytes.split_at(REGISTERS - 1);
+                for (index, value) in bytes.chunks(1).enumerate() {
+                    set_register(nes, index, value);
+                }
+                set_register(nes, REGISTERS - 1, pc);
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(index) if index < REGISTERS => {
+                    let registers = registers(nes);
+                    hex(if index == 5 { &registers[5..7] } else { &registers[index..index + 1] })
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let written = args.split_once('=').and_then(|(index, value)| {
+                    Some(set_register(nes, usize::from_str_radix(index, 16).ok()?, &decode_hex(value)?))
+                });
+                if written == Some(true) { "OK".to_string() } else { "E01".to_string() }
+            }
+            "m" => match parse_range(args) {
+                // A shorter reply than asked for is fine, GDB reads the rest next
+                Some((addr, len)) => {
+                    let len = len.min((PACKET_SIZE / 2) as u16);
+                    let bytes: Vec<u8> = (0..len).map(|i| nes.bus().peek(addr.wrapping_add(i))).collect();
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)));
+                match write {
+                    // The bus can't write cartridge ROM
+                    Some(((addr, len), data)) if data.len() == len as usize && (addr as usize + data.len()) <= CARTRIDGE_ROM as usize => {
+                        for (i, byte) in data.iter().enumerate() {
+                            nes.cpu_mut().mem_write(addr + i as u16, *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(nes, command == "Z", args),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            _ => self.query(packet),
+        }
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+", PACKET_SIZE);
+        }
+
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',').and_then(|(offset, len)| {
+                Some((usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+            }) else {
+                return "E01".to_string();
+            };
+
+            let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+            return if chunk.len() > len { format!("m{}", &chunk[..len]) } else { format!("l{}", chunk) };
+        }
+
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2/Z3/Z4 write, read and access watchpoints
+    fn breakpoint(&mut self, nes: &mut Nes, insert: bool, args: &str) -> String {
+        let mut parts = args.splitn(2, ',');
+        let (Some(kind), Some((addr, len))) = (parts.next().and_then(|kind| kind.parse::<u8>().ok()), parts.next().and_then(parse_range)) else {
+            return "E01".to_string();
+        };
+
+        match (kind, insert) {
+            (0 | 1, true) => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+            }
+            (0 | 1, false) => self.breakpoints.retain(|breakpoint| *breakpoint != addr),
+            (2..=4, true) => {
+                let access = match kind {
+                    2 => Access::WRITE,
+                    3 => Access::READ,
+                    _ => Access::READ | Access::WRITE,
+                };
+                let end = addr.saturating_add(len.max(1) - 1);
+                let id = nes.bus_mut().watchpoints_mut().add(addr, end, access, None);
+                self.watches.push(Watch { kind, addr, len, id });
+            }
+            (2..=4, false) => {
+                if let Some(i) = self.watches.iter().position(|w| (w.kind, w.addr, w.len) == (kind, addr, len)) {
+                    let watch = self.watches.remove(i);
+                    nes.bus_mut().watchpoints_mut().remove(watch.id);
+                }
+            }
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+
+    // Runs until a breakpoint, a watchpoint, one instruction when stepping, or
+    // an interrupt from GDB. BRK halts the CPU for good, so it's reported as
+    // the program exiting
+    fn resume(&mut self, nes: &mut Nes, step: bool) -> io::Result<String> {
+        // Drop hits from memory GDB read while stopped
+        nes.bus().watchpoints().take_hit();
+
+        let mut first = true;
+        let mut reply = None;
+
+        while reply.is_none() {
+            nes.run_frame_with(|nes| {
+                if let Some(hit) = nes.bus().watchpoints().take_hit() {
+                    let kind = self.watches.iter().find(|watch| watch.id == hit.id).map_or(2, |watch| watch.kind);
+                    let name = ["watch", "rwatch", "awatch"][kind as usize - 2];
+                    reply = Some(format!("T{:02x}{}:{:04x};", SIGTRAP, name, hit.addr));
+                } else if std::mem::take(&mut first) {
+                    return true;
+                } else if step {
+                    reply = Some(format!("S{:02x}", SIGTRAP));
+                } else if self.breakpoints.contains(&nes.cpu().program_counter) {
+                    reply = Some(format!("T{:02x}swbreak:;", SIGTRAP));
+                }
+                reply.is_none()
+            });
+
+            if nes.is_halted() {
+                return Ok("W00".to_string());
+            }
+            if reply.is_none() && self.interrupted()? {
+                reply = Some(format!("S{:02x}", SIGINT));
+            }
+        }
+
+        Ok(reply.unwrap())
+    }
+
+    // Checked between frames, GDB sends a bare 0x03 to stop the target
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut byte = [0];
+        self.stream.set_nonblocking(true)?;
+        let read = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match read {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Err(ErrorKind::UnexpectedEof.into()),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // `$data#checksum`, acked with + or - unless no-ack mode is on. Returns
+    // None once GDB disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acks and stray interrupts between packets are skipped
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum)?;
+            let valid = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok()) == Some(checksum(&data));
+
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid || !self.ack {
+                return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let mut data = vec![];
+        for byte in reply.bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                data.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                data.push(byte);
+            }
+        }
+
+        let packet = [b"$".as_slice(), &data, format!("#{:02x}", checksum(&data)).as_bytes()].concat();
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.ack {
+                return Ok(());
+            }
+
+            // Resend until GDB acks it
+            match self.read_byte()? {
+                Some(b'+') | None => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::test_util::nestest;
+    use std::net::TcpListener;
+
+    // Sends a packet and returns the reply, acking it
+    fn request(stream: &mut TcpStream, packet: &str) -> String {
+        write!(stream, "${}#{:02x}", packet, checksum(packet.as_bytes())).unwrap();
+
+        let mut bytes = vec![];
+        let mut byte = [0];
+        while bytes.last() != Some(&b'#') {
+            stream.read_exact(&mut byte).unwrap();
+            if !(bytes.is_empty() && byte[0] == b'+') {
+                bytes.push(byte[0]);
+            }
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        stream.write_all(b"+").unwrap();
+
+        String::from_utf8(bytes[1..bytes.len() - 1].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_session_over_loopback() {
+        let mut nes = nestest();
+        nes.cpu_mut().program_counter = 0xC000;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut gdb = TcpStream::connect(addr).unwrap();
+            gdb.set_nodelay(true).unwrap();
+            let mut replies = vec![];
+            for packet in ["qSupported:swbreak+", "g", "mc000,3", "Z0,c72d,1", "c", "p5", "s", "p5", "Z2,0000,1", "c", "P0=42", "p0", "m0000,ffff", "D"] {
+                replies.push(request(&mut gdb, packet));
+            }
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(stream).serve(&mut nes).unwrap();
+        let replies = client.join().unwrap();
+
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], "000000fd2400c0");
+        // JMP $C5F5
+        assert_eq!(replies[2], "4cf5c5");
+        assert_eq!(&replies[4..8], ["T05swbreak:;", "2dc7", "S05", "2ec7"]);
+        // nestest clears $00 soon after
+        assert_eq!(replies[9], "T05watch:0000;");
+        assert_eq!(&replies[10..12], ["OK", "42"]);
+        assert_eq!(replies[12].len(), PACKET_SIZE);
+        assert_eq!(replies[13], "OK");
+        assert!(nes.bus().watchpoints().list().is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::bus::Bus;
+use crate::nes::SAMPLE_RATE;
+use crate::screen;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+const RAM_SIZE: u16 = 0x0800;
+
+// Condition on a RAM byte that stops a headless run, e.g. `$00F0==$01`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RamCondition {
+    pub addr: u16,
+    pub value: u8,
+    pub equal: bool,
+}
+
+// `$` or `0x` prefixed hex, or decimal
+pub fn parse_number(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let parsed = if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+
+    parsed.map_err(|_| format!("Invalid number: {}", text))
+}
+
+impl FromStr for RamCondition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (addr, value, equal) = if let Some((addr, value)) = text.split_once("==") {
+            (addr, value, true)
+        } else if let Some((addr, value)) = text.split_once("!=") {
+            (addr, value, false)
+        } else {
+            return Err(format!("Expected ADDR==VALUE or ADDR!=VALUE, got {}", text));
+        };
+
+        let value = parse_number(value)?;
+        if value > 0xFF {
+            return Err(format!("Value {} doesn't fit in a byte", value));
+        }
+
+        let addr = parse_number(addr)?;
+        if addr > 0xFFFF {
+            return Err(format!("Address {} is out of range", addr));
+        }
+
+        Ok(RamCondition { addr: addr as u16, value: value as u8, equal })
+    }
+}
+
+impl RamCondition {
+    pub fn holds(&self, bus: &Bus) -> bool {
+        (bus.peek(self.addr) == self.value) == self.equal
+    }
+}
+
+// Internal 2K of CPU RAM
+pub fn dump_ram(bus: &Bus) -> Vec<u8> {
+    (0..RAM_SIZE).map(|addr| bus.peek(addr)).collect()
+}
+
+pub fn write_png(path: &Path, frame: &screen::Frame) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?), screen::WIDTH as u32, screen::HEIGHT as u32
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(frame)?;
+
+    Ok(())
+}
+
+// 16-bit mono PCM
+pub fn write_wav(path: &Path, samples: &[i16]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let data_size = (samples.len() * 2) as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM, 1 channel
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    // Byte rate, block align, bits per sample
+    writer.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ram_condition() {
+        assert_eq!(
+            "$00F0==$01".parse::<RamCondition>(),
+            Ok(RamCondition { addr: 0xF0, value: 1, equal: true })
+        );
+        assert_eq!(
+            "16!=0x20".parse::<RamCondition>(),
+            Ok(RamCondition { addr: 16, value: 0x20, equal: false })
+        );
+        assert!("$10=1".parse::<RamCondition>().is_err());
+        assert!("$10==$100".parse::<RamCondition>().is_err());
+    }
+}
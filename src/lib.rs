@@ -0,0 +1,33 @@
+//! FamEmu core: 6502 CPU, system bus and cartridge loading.
+//!
+//! ```no_run
+//! use famemu::{JoypadButtons, Nes, Rom};
+//!
+//! let rom = Rom::new(&std::fs::read("game.nes").unwrap()).unwrap();
+//! let mut nes = Nes::new(rom);
+//! nes.set_input(0, JoypadButtons::START);
+//! let frame = nes.run_frame();
+//! ```
+
+pub mod bus;
+pub mod cdl;
+pub mod cpu;
+pub mod debugger;
+pub mod gdb;
+pub mod headless;
+pub mod movie;
+pub mod nes;
+pub mod profiler;
+pub mod rewind;
+pub mod rom;
+pub mod savestate;
+pub mod screen;
+pub mod symbols;
+pub mod tracer;
+
+pub use bus::joypad::JoypadButtons;
+pub use bus::{Bus, RamInit};
+pub use cpu::cpu::{CpuFlags, CPU};
+pub use cpu::mem::Mem;
+pub use nes::Nes;
+pub use rom::{Mirroring, Rom, RomError, Timing};
@@ -1,119 +1,728 @@
-mod cpu;
-mod bus;
-mod rom;
-
-use std::fs;
-
-use bus::Bus;
-use cpu::trace::trace;
-use cpu::cpu::CPU;
-use cpu::mem::Mem;
-use rand::Rng;
-use rom::Rom;
-use sdl2::event::Event;
-use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::{Color, PixelFormatEnum};
-use sdl2::render::TextureAccess;
-
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                println!("Quit");
-                std::process::exit(0)
-            },
-            Event::KeyDown { keycode: Some(Keycode::W), .. } => {
-                cpu.mem_write(0xFF, 0x77);
-            },
-            Event::KeyDown { keycode: Some(Keycode::A), .. } => {
-                cpu.mem_write(0xFF, 0x61);
-            },
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => {
-                cpu.mem_write(0xFF, 0x73);
-            },
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => {
-                cpu.mem_write(0xFF, 0x64);
+mod cli;
+#[cfg(feature = "sdl")]
+mod frontend;
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use cli::{Cli, Command, EmulationArgs, RamInitArg, RomArgs, RunArgs, TraceArgs};
+use famemu::bus::battery::BatterySave;
+use famemu::cdl::CodeDataLog;
+use famemu::cpu::disasm::Disassembler;
+use famemu::debugger::{Condition, Debugger};
+use famemu::gdb::GdbStub;
+use famemu::rom::{archive, patch};
+use famemu::headless;
+use famemu::movie::{Movie, Player, Recorder};
+use famemu::profiler::Profiler;
+use famemu::savestate;
+use famemu::symbols::{self, Location, Symbols, PRG_BANK_SIZE};
+use famemu::tracer::Tracer;
+use famemu::{Bus, Nes, RamInit, Rom, Timing};
+
+// Reads, patches and parses the ROM, exits with a message on failure
+fn load_rom(args: &RomArgs) -> Rom {
+    let rom_path = &args.rom;
+
+    let image = match archive::read_image(rom_path, args.entry.as_deref()) {
+        Ok(image) => image,
+        Err(err) => {
+            println!("Failed to read {}: {}", rom_path.display(), err);
+            std::process::exit(1)
+        }
+    };
+    if image.roms.len() > 1 && args.entry.is_none() {
+        println!("Archive has several ROMs, pick one with --entry: {}", image.roms.join(", "));
+    }
+    let mut game_code = image.data;
+
+    // A patch given with --patch wins over one found next to the ROM
+    let patch_path = args.patch.clone().or_else(|| patch::find_patch(rom_path));
+
+    if let Some(patch_path) = patch_path {
+        game_code = match patch::apply_file(&patch_path, &game_code) {
+            Ok(patched) => patched,
+            Err(err) => {
+                println!("Failed to apply {}: {}", patch_path.display(), err);
+                std::process::exit(1)
             }
+        };
+        println!("Applied patch {}", patch_path.display());
+    }
 
-            _ => { /* DO NOTHING */}
+    match Rom::new(&game_code) {
+        Ok(rom) => rom,
+        Err(err) => {
+            println!("Failed to load {}: {}", rom_path.display(), err);
+            std::process::exit(1)
         }
     }
 }
 
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => Color::BLACK,
-        1 => Color::WHITE,
-        2 | 9 => Color::GRAY,
-        3 | 10 => Color::RED,
-        4 | 11 => Color::GREEN,
-        5 | 12 => Color::BLUE,
-        6 | 13 => Color::MAGENTA,
-        7 | 14 => Color::YELLOW,
-        _ => Color::CYAN
+fn print_info(rom: &Rom) {
+    println!("Format:           {:?}", rom.format);
+    println!("Mapper:           {} (submapper {})", rom.mapper, rom.submapper);
+    println!("PRG-ROM:          {} bytes", rom.prg_rom.len());
+    println!("CHR-ROM:          {} bytes", rom.chr_rom.len());
+    println!("PRG-RAM:          {} bytes", rom.prg_ram_size);
+    println!("PRG-NVRAM:        {} bytes", rom.prg_nvram_size);
+    println!("CHR-RAM:          {} bytes", rom.chr_ram_size);
+    println!("CHR-NVRAM:        {} bytes", rom.chr_nvram_size);
+    println!("Mirroring:        {:?}", rom.screen_mirroring);
+    println!("Battery:          {}", rom.battery);
+    println!("Trainer:          {}", rom.trainer.is_some());
+    println!("Timing:           {:?}", rom.timing);
+    println!("Console:          {:?}", rom.console_type);
+    println!("Misc ROMs:        {}", rom.misc_roms);
+    println!("Expansion device: {}", rom.expansion_device);
+    println!("CRC32:            {:08X}", rom.crc32);
+    println!("SHA-1:            {}", rom.sha1);
+
+    if let Some(game) = &rom.game {
+        println!("Title:            {}", game.title);
+        println!("Region:           {:?}", game.region);
     }
+
+    for warning in &rom.warnings {
+        println!("Warning:          {:?}", warning);
+    }
+}
+
+fn timing(rom: &Rom, args: &EmulationArgs) -> Timing {
+    args.region.map(Timing::from).unwrap_or(rom.timing)
 }
 
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 32 * 3]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
+fn ram_init(args: &EmulationArgs) -> RamInit {
+    match args.ram_init {
+        RamInitArg::Zeros => RamInit::Zeros,
+        RamInitArg::Ones => RamInit::Ones,
+        RamInitArg::Pattern => RamInit::Pattern,
+        RamInitArg::Random => {
+            let seed = args.seed.unwrap_or_else(rand::random);
+            println!("RAM init seed {}", seed);
+            RamInit::Random(seed)
+        }
+    }
+}
 
-    for i in 0x0200..0x0600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
+// Runs until BRK, the frame limit, or until `callback` returns false. The
+// callback is called before every instruction
+fn run_frames<F>(nes: &mut Nes, frame_limit: Option<usize>, mut callback: F)
+where
+    F: FnMut(&mut Nes) -> bool
+{
+    while frame_limit.is_none_or(|limit| nes.frame_count() < limit) {
+        if !nes.run_frame_with(&mut callback) {
+            return;
+        }
+    }
+}
 
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
+// Symbol files given with --symbols, or those found next to the ROM. Exits
+// with a message if one given on the command line can't be loaded
+fn load_symbols(args: &RomArgs) -> Symbols {
+    let mut symbols = Symbols::default();
+    let given = !args.symbols.is_empty();
+    let paths = if given { args.symbols.clone() } else { symbols::find_files(&args.rom) };
 
-            update = true;
+    for path in paths {
+        match symbols.load_file(&path) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, path.display()),
+            Err(err) => {
+                println!("Failed to load {}: {}", path.display(), err);
+                if given {
+                    std::process::exit(1)
+                }
+            }
         }
-        frame_idx += 3;
     }
 
-    update
+    symbols
 }
 
-fn main() {
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-            .window("FamEmu: Testing Snake", 320 as u32, 320 as u32)
-            .position_centered()
-            .build().unwrap();
+// The log to add to, a new one if the file doesn't exist yet
+fn load_cdl(path: &Path, rom: &Rom) -> CodeDataLog {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return CodeDataLog::new(rom),
+        Err(err) => {
+            println!("Failed to read {}: {}", path.display(), err);
+            std::process::exit(1)
+        }
+    };
+
+    match CodeDataLog::from_cdl(&data, rom) {
+        Ok(cdl) => {
+            println!("Loaded {}: {}", path.display(), cdl.stats());
+            cdl
+        }
+        Err(err) => {
+            println!("Failed to load {}: {}", path.display(), err);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn save_cdl(nes: &Nes, emulation: &EmulationArgs) {
+    if let (Some(path), Some(cdl)) = (&emulation.cdl, nes.cdl()) {
+        match fs::write(path, cdl.to_cdl()) {
+            Ok(_) => println!("Wrote {}: {}", path.display(), cdl.stats()),
+            Err(err) => println!("Failed to write {}: {}", path.display(), err),
+        }
+    }
+}
+
+// Rows in the profile summary, the folded stacks have everything
+const PROFILE_ROUTINES: usize = 20;
+
+// The profile's summary goes to stdout, its folded stacks to the file
+fn save_profile(nes: &Nes, emulation: &EmulationArgs) {
+    let (Some(path), Some(profiler)) = (&emulation.profile, nes.profiler()) else { return };
+
+    print!("{}", profiler.report(nes.bus(), PROFILE_ROUTINES));
+    let result = File::create(path).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        profiler.write_folded(nes.bus(), &mut out)?;
+        out.flush()
+    });
+    match result {
+        Ok(_) => println!("Wrote {}: {} frames profiled", path.display(), profiler.frames()),
+        Err(err) => println!("Failed to write {}: {}", path.display(), err),
+    }
+}
+
+fn power_on(rom: Rom, rom_args: &RomArgs, emulation: &EmulationArgs) -> (Nes, Option<BatterySave>) {
+    let rom_path = &rom_args.rom;
+    let timing = timing(&rom, emulation);
+    let mut nes = Nes::new(rom);
+    nes.set_timing(timing);
+    nes.set_ram_init(ram_init(emulation));
+    nes.set_snake_io(emulation.snake_io);
+    if let Some(path) = &emulation.cdl {
+        nes.set_cdl(Some(load_cdl(path, nes.rom())));
+    }
+    if emulation.profile.is_some() {
+        nes.set_profiler(Some(Profiler::new()));
+    }
+    nes.power_on();
+    nes.bus_mut().set_symbols(load_symbols(rom_args));
+
+    let battery = if nes.bus().is_battery_backed() {
+        let battery = BatterySave::new(rom_path);
+        if let Err(err) = battery.load(nes.bus_mut()) {
+            println!("Failed to read {}: {}", battery.path().display(), err);
+        }
+        Some(battery)
+    } else {
+        None
+    };
+
+    (nes, battery)
+}
+
+// Movie being recorded or played by `run`
+pub struct MovieSession {
+    recorder: Option<Recorder>,
+    player: Option<Player>,
+    record_path: Option<PathBuf>,
+}
+
+impl MovieSession {
+    // Exits with a message if the movie to play can't be loaded
+    fn new(nes: &mut Nes, args: &RunArgs) -> Self {
+        let mut session = MovieSession { recorder: None, player: None, record_path: args.record.clone() };
+
+        if let Some(path) = &args.play {
+            let movie = fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Movie::from_fm2(&text).map_err(|err| err.to_string()))
+                .and_then(|movie| Player::new(movie, nes).map_err(|err| err.to_string()));
+
+            match movie {
+                Ok(player) => {
+                    println!("Playing {} ({} frames)", path.display(), player.movie().frames.len());
+                    session.player = Some(player);
+                }
+                Err(err) => {
+                    println!("Failed to play {}: {}", path.display(), err);
+                    std::process::exit(1)
+                }
+            }
+        }
+
+        if let Some(path) = &args.record {
+            let rom_name = args.rom.rom.file_stem().unwrap_or_default().to_string_lossy();
+            let from_state = args.load_state.is_some();
+            session.recorder = Some(Recorder::new(nes, &rom_name, from_state, args.hash_interval));
+            println!("Recording to {}", path.display());
+        }
+
+        session
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.recorder.is_some() || self.player.is_some()
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.player.is_some()
+    }
+
+    // Soft or hard reset, recorded into the movie if there is one
+    pub fn reset(&mut self, nes: &mut Nes, hard: bool) {
+        match &mut self.recorder {
+            Some(recorder) if hard => recorder.power_on(),
+            Some(recorder) => recorder.reset(),
+            None if hard => nes.power_on(),
+            None => nes.reset(),
+        }
+    }
+
+    // Returns false when the movie being played is over
+    pub fn begin_frame(&mut self, nes: &mut Nes) -> bool {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.begin_frame(nes);
+        }
+
+        match &mut self.player {
+            Some(player) => player.begin_frame(nes),
+            None => true,
+        }
+    }
+
+    // Returns false on a desync
+    pub fn end_frame(&mut self, nes: &Nes) -> bool {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.end_frame(nes);
+        }
+
+        match self.player.as_mut().map(|player| player.end_frame(nes)) {
+            Some(Err(desync)) => {
+                println!("{}", desync);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn finish(self) {
+        if let Some(player) = &self.player {
+            println!("Played {} of {} frames", player.frame(), player.movie().frames.len());
+        }
+
+        if let (Some(recorder), Some(path)) = (self.recorder, &self.record_path) {
+            let movie = recorder.finish();
+            match fs::write(path, movie.to_fm2()) {
+                Ok(_) => println!("Recorded {} frames to {}", movie.frames.len(), path.display()),
+                Err(err) => println!("Failed to write {}: {}", path.display(), err),
+            }
+        }
+    }
+}
+
+fn flush_battery(battery: &mut Option<BatterySave>, bus: &mut Bus) {
+    if let Some(battery) = battery {
+        if let Err(err) = battery.flush(bus) {
+            println!("Failed to write {}: {}", battery.path().display(), err);
+        }
+    }
+}
+
+fn run(args: RunArgs) {
+    if !args.headless && !cfg!(feature = "sdl") {
+        println!("FamEmu was built without the sdl feature, use --headless");
+        std::process::exit(1)
+    }
+
+    let rom = load_rom(&args.rom);
+
+    match &rom.game {
+        Some(game) => println!("{} ({:?})", game.title, game.region),
+        None => println!("Unknown game, CRC32 {:08X}", rom.crc32),
+    }
+
+    #[cfg(feature = "sdl")]
+    let title = match rom.title() {
+        Some(title) => format!("FamEmu: {}", title),
+        None => "FamEmu".to_string(),
+    };
+
+    let (mut nes, mut battery) = power_on(rom, &args.rom, &args.emulation);
+
+    if let Some(slot) = args.load_state {
+        if let Err(err) = savestate::load_slot(&mut nes, &args.rom.rom, slot) {
+            println!("Failed to load state: {}", err);
+            std::process::exit(1)
+        }
+    }
+
+    let mut movie = MovieSession::new(&mut nes, &args);
+    let mut tracer = args.trace.as_ref().map(|path| {
+        let mut tracer = create_tracer(path, &args.trace_args, &nes);
+        tracer.set_enabled(!args.trace_paused);
+        tracer
+    });
+
+    if args.headless {
+        run_headless(&mut nes, &args, &mut battery, &mut movie, &mut tracer);
+    } else {
+        #[cfg(feature = "sdl")]
+        frontend::run_window(&mut nes, &args, &title, &mut battery, &mut movie, &mut tracer);
+    }
+
+    if let (Some(tracer), Some(path)) = (tracer, &args.trace) {
+        finish_trace(tracer, path);
+    }
+    movie.finish();
+    flush_battery(&mut battery, nes.bus_mut());
+    save_cdl(&nes, &args.emulation);
+    save_profile(&nes, &args.emulation);
+}
+
+pub type FileTracer = Tracer<BufWriter<File>>;
+
+// Exits with a message if the file can't be created or the condition is bad
+fn create_tracer(path: &Path, args: &TraceArgs, nes: &Nes) -> FileTracer {
+    let mut tracer = match Tracer::create(path, args.trace_format.into()) {
+        Ok(tracer) => tracer,
+        Err(err) => {
+            println!("Failed to create {}: {}", path.display(), err);
+            std::process::exit(1)
+        }
+    };
+
+    if let Some((start, end)) = args.trace_pc {
+        tracer.set_pc_range(start, end);
+    }
+    if let Some(bank) = args.trace_bank {
+        tracer.set_bank(bank);
+    }
+    if let Some(condition) = &args.trace_when {
+        match Condition::parse_all(condition, nes.bus()) {
+            Ok(conditions) => tracer.set_trigger(conditions),
+            Err(err) => {
+                println!("Bad --trace-when: {}", err);
+                std::process::exit(1)
+            }
+        }
+    }
+
+    tracer
+}
+
+// Writes the trace line for the instruction at PC. A write error stops
+// tracing instead of the emulation
+pub fn log_trace(tracer: &mut Option<FileTracer>, nes: &Nes) {
+    if let Some(err) = tracer.as_mut().and_then(|tracer| tracer.log(nes).err()) {
+        println!("Failed to write the trace: {}", err);
+        *tracer = None;
+    }
+}
+
+fn finish_trace(mut tracer: FileTracer, path: &Path) {
+    match tracer.flush() {
+        Ok(_) => println!("Wrote {} trace lines to {}", tracer.lines(), path.display()),
+        Err(err) => println!("Failed to write {}: {}", path.display(), err),
+    }
+}
+
+fn run_headless(
+    nes: &mut Nes, args: &RunArgs, battery: &mut Option<BatterySave>, movie: &mut MovieSession, tracer: &mut Option<FileTracer>
+) {
+    let mut audio = vec![];
+
+    while args.emulation.frames.is_none_or(|limit| nes.frame_count() < limit) {
+        if !movie.begin_frame(nes) {
+            break;
+        }
+
+        let running = nes.run_frame_with(|nes| {
+            log_trace(tracer, nes);
+
+            if let Some(battery) = battery.as_mut() {
+                if let Err(err) = battery.tick(nes.bus_mut()) {
+                    println!("Failed to write {}: {}", battery.path().display(), err);
+                }
+            }
+
+            !args.until.is_some_and(|condition| condition.holds(nes.bus()))
+        });
+
+        if args.wav.is_some() {
+            audio.extend_from_slice(nes.audio_samples());
+        }
+
+        if !running || !movie.end_frame(nes) {
+            break;
+        }
+    }
+
+    println!("Stopped after {} frames ({} CPU cycles)", nes.frame_count(), nes.bus().cycles());
+
+    if let Some(path) = &args.png {
+        if let Err(err) = headless::write_png(path, nes.frame()) {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+
+    if let Some(path) = &args.ram_dump {
+        if let Err(err) = fs::write(path, headless::dump_ram(nes.bus())) {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+
+    if let Some(path) = &args.wav {
+        if let Err(err) = headless::write_wav(path, &audio) {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn write_trace(rom_args: RomArgs, out: &Path, trace: TraceArgs, emulation: EmulationArgs) {
+    let rom = load_rom(&rom_args);
+    let (mut nes, _) = power_on(rom, &rom_args, &emulation);
+    let mut tracer = create_tracer(out, &trace, &nes);
+
+    let mut result = Ok(());
+    run_frames(&mut nes, emulation.frames, |nes| {
+        result = tracer.log(nes);
+        result.is_ok()
+    });
+
+    save_cdl(&nes, &emulation);
+    save_profile(&nes, &emulation);
+
+    if let Err(err) = result.and_then(|_| tracer.flush()) {
+        println!("Failed to write {}: {}", out.display(), err);
+        std::process::exit(1)
+    }
+}
+
+fn debug(rom_args: RomArgs, emulation: EmulationArgs) {
+    let rom = load_rom(&rom_args);
+    let (mut nes, _) = power_on(rom, &rom_args, &emulation);
+    let mut debugger = Debugger::new();
+    let (mut input, mut out) = (std::io::stdin().lock(), std::io::stdout());
+    println!("Type help for the list of commands");
+
+    loop {
+        let mut quit = false;
+        run_frames(&mut nes, emulation.frames, |nes| {
+            quit = debugger.should_break(nes) && !debugger.repl(nes, &mut input, &mut out);
+            !quit
+        });
+
+        if quit || !nes.is_halted() {
+            break;
+        }
+
+        // BRK halts the CPU, stay in the debugger so the state can be looked at
+        // and the machine reset
+        println!("CPU halted by BRK");
+        debugger.pause();
+        if !debugger.repl(&mut nes, &mut input, &mut out) {
+            break;
+        }
+    }
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
+    println!("Stopped after {} frames ({} CPU cycles)", nes.frame_count(), nes.bus().cycles());
+    save_cdl(&nes, &emulation);
+    save_profile(&nes, &emulation);
+}
+
+fn serve_gdb(rom_args: RomArgs, port: u16, emulation: EmulationArgs) {
+    let rom = load_rom(&rom_args);
+    let (mut nes, _) = power_on(rom, &rom_args, &emulation);
+
+    let connection = std::net::TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        println!("Waiting for GDB on 127.0.0.1:{}", port);
+        listener.accept()
+    });
+
+    let result = connection.and_then(|(stream, peer)| {
+        println!("GDB connected from {}", peer);
+        GdbStub::new(stream).serve(&mut nes)
+    });
+
+    save_cdl(&nes, &emulation);
+    save_profile(&nes, &emulation);
+
+    match result {
+        Ok(_) => println!("GDB detached after {} frames", nes.frame_count()),
+        Err(err) => {
+            println!("GDB connection failed: {}", err);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn disassemble(rom_args: RomArgs, bank: Option<usize>, base: Option<u16>, entries: &[u16], cdl: Option<&Path>, out: Option<&Path>) {
+    let rom = load_rom(&rom_args);
+    let banks = rom.prg_rom.len() / PRG_BANK_SIZE;
+
+    let (data, offset, default_base) = match bank {
+        Some(bank) if bank >= banks => {
+            println!("PRG-ROM has {} banks of 16K", banks);
+            std::process::exit(1)
+        }
+        Some(bank) => {
+            let base = if bank + 1 == banks { 0xC000 } else { 0x8000 };
+            (&rom.prg_rom[bank * PRG_BANK_SIZE..(bank + 1) * PRG_BANK_SIZE], bank * PRG_BANK_SIZE, base)
+        }
+        None if rom.prg_rom.len() <= 0x8000 => (&rom.prg_rom[..], 0, (0x10000 - rom.prg_rom.len()) as u16),
+        None => {
+            println!("PRG-ROM is larger than 32K, pick a bank with --bank");
+            std::process::exit(1)
+        }
+    };
+
+    let base = base.unwrap_or(default_base);
+    let mut disassembler = Disassembler::new(data, base);
+    for entry in entries {
+        disassembler.add_entry(*entry);
+    }
+
+    // Code seen running in a logged session
+    if let Some(path) = cdl {
+        for start in load_cdl(path, &rom).code_starts() {
+            if (offset..offset + data.len()).contains(&start) {
+                disassembler.add_entry(base.wrapping_add((start - offset) as u16));
+            }
+        }
+    }
+
+    // Labels of other banks don't apply, and cheap locals like @loop can't be
+    // written out without the scope they belong to
+    let mut names = HashSet::new();
+    for symbol in load_symbols(&rom_args).list() {
+        let addr = match symbol.location {
+            Location::Prg(prg) if (offset..offset + data.len()).contains(&prg) => base.wrapping_add((prg - offset) as u16),
+            Location::Prg(_) => continue,
+            Location::Cpu(addr) => addr,
+        };
+        if is_identifier(&symbol.name) && names.insert(symbol.name.as_str()) {
+            disassembler.add_label(addr, &symbol.name);
+        }
+    }
+    let source = disassembler.to_ca65();
+
+    let result = match out {
+        Some(path) => fs::write(path, source).map_err(|err| format!("Failed to write {}: {}", path.display(), err)),
+        None => {
+            print!("{}", source);
+            Ok(())
+        }
+    };
 
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture(PixelFormatEnum::RGB24, TextureAccess::Static, 32, 32).unwrap();
+    if let Err(err) = result {
+        println!("{}", err);
+        std::process::exit(1)
+    }
+}
 
-    let game_code = fs::read("/Users/alexey/Documents/Prog/Rust/FamEmu/snake.nes").unwrap();
-    let rom = Rom::new(&game_code).unwrap();
-    let bus = Bus::new(rom);
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-    let mut cpu = CPU::new(bus);
-    cpu.reset();
+// blargg's test ROM protocol: $6000 holds the status, $6001-$6003 the signature
+// DE B0 61 and $6004 a zero terminated message
+const TEST_STATUS: u16 = 0x6000;
+const TEST_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEST_MESSAGE: u16 = 0x6004;
+const TEST_RUNNING: u8 = 0x80;
+const TEST_NEEDS_RESET: u8 = 0x81;
+const TEST_DEFAULT_FRAMES: usize = 60 * 60;
 
-    let mut screen_state = [0 as u8; 32 * 32 * 3];
-    let mut rng = rand::thread_rng();
+fn test_result(bus: &Bus) -> Option<u8> {
+    let signature = [bus.peek(TEST_STATUS + 1), bus.peek(TEST_STATUS + 2), bus.peek(TEST_STATUS + 3)];
+
+    if signature == TEST_SIGNATURE {
+        Some(bus.peek(TEST_STATUS))
+    } else {
+        None
+    }
+}
 
-    cpu.run_with_callback(move |cpu| {
-        println!("{}", trace(cpu));
+fn test_message(bus: &Bus) -> String {
+    let mut message = vec![];
+    let mut addr = TEST_MESSAGE;
 
-        handle_user_input(cpu, &mut event_pump);
-        cpu.mem_write(0xFE, rng.gen_range(1..16));
-        
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+    while addr < 0x8000 {
+        let byte = bus.peek(addr);
+        if byte == 0 {
+            break;
         }
+        message.push(byte);
+        addr += 1;
+    }
+
+    String::from_utf8_lossy(&message).to_string()
+}
 
-        std::thread::sleep(std::time::Duration::new(0, 100_000));
+fn run_test(rom_args: RomArgs, emulation: EmulationArgs) {
+    let rom = load_rom(&rom_args);
+    let (mut nes, _) = power_on(rom, &rom_args, &emulation);
+    let frame_limit = emulation.frames.unwrap_or(TEST_DEFAULT_FRAMES);
+
+    // The ROM asks for a reset by writing $81, it has to happen at least 100ms later
+    let reset_delay = nes.timing().cpu_cycles_per_frame() * 6;
+    let mut reset_at = None;
+
+    run_frames(&mut nes, Some(frame_limit), |nes| {
+        match test_result(nes.bus()) {
+            Some(TEST_RUNNING) => true,
+            Some(TEST_NEEDS_RESET) => {
+                match reset_at {
+                    None => reset_at = Some(nes.bus().cycles() + reset_delay),
+                    Some(at) if nes.bus().cycles() >= at => {
+                        reset_at = None;
+                        nes.reset();
+                    }
+                    _ => {}
+                }
+                true
+            }
+            Some(_) => false,
+            None => true,
+        }
     });
-}
\ No newline at end of file
+
+    save_cdl(&nes, &emulation);
+    save_profile(&nes, &emulation);
+
+    let message = test_message(nes.bus());
+    match test_result(nes.bus()) {
+        Some(status) if status < TEST_RUNNING => {
+            println!("{}", message.trim_end());
+            println!("{}", if status == 0 { "Passed" } else { "Failed" });
+            std::process::exit(status as i32)
+        }
+        Some(_) => {
+            println!("{}", message.trim_end());
+            println!("Test didn't finish in {} frames", frame_limit);
+            std::process::exit(0xFF)
+        }
+        None => {
+            println!("ROM doesn't report a test result at $6000");
+            std::process::exit(0xFF)
+        }
+    }
+}
+
+fn main() {
+    match Cli::parse().command {
+        Command::Run(args) => run(args),
+        Command::Info { rom } => print_info(&load_rom(&rom)),
+        Command::Trace { rom, out, trace, emulation } => write_trace(rom, &out, trace, emulation),
+        Command::Debug { rom, emulation } => debug(rom, emulation),
+        Command::Gdb { rom, port, emulation } => serve_gdb(rom, port, emulation),
+        Command::Disasm { rom, bank, base, entry_point, cdl, out } => {
+            disassemble(rom, bank, base, &entry_point, cdl.as_deref(), out.as_deref())
+        }
+        Command::Test { rom, emulation } => run_test(rom, emulation),
+    }
+}
@@ -0,0 +1,574 @@
+use crate::bus::joypad::JoypadButtons;
+use crate::bus::RamInit;
+use crate::nes::Nes;
+use crate::rom::{Rom, Timing};
+use crate::savestate::{self, StateError};
+
+use std::fmt::Write;
+
+mod md5;
+
+// Frame commands, the first column of an FM2 input line
+pub const SOFT_RESET: u8 = 1;
+pub const HARD_RESET: u8 = 2;
+
+// FM2 draws a controller as RLDUTSBA, i.e. from the highest bit down
+const BUTTON_CHARS: &[u8; 8] = b"RLDUTSBA";
+
+pub const DEFAULT_HASH_INTERVAL: usize = 60;
+
+#[derive(Debug, PartialEq)]
+pub enum MovieError {
+    Parse { line: usize, message: String },
+    // `expected` is the checksum in the movie, `actual` the loaded ROM's
+    RomMismatch { expected: String, actual: String },
+    // FCEUX movies that start from an FCEUX save state can't be played
+    ForeignSavestate,
+    State(StateError),
+}
+
+impl std::fmt::Display for MovieError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "Line {}: {}", line, message),
+            MovieError::RomMismatch { expected, actual } => write!(
+                f, "Movie was recorded with another ROM ({}, loaded {})", expected, actual
+            ),
+            MovieError::ForeignSavestate => write!(f, "Movie starts from an FCEUX save state"),
+            MovieError::State(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        MovieError::State(err)
+    }
+}
+
+// RAM hash of the playback didn't match the recording
+#[derive(Debug, PartialEq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for Desync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Desync at frame {}: RAM hash {:08X}, expected {:08X}", self.frame, self.actual, self.expected)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub ports: [JoypadButtons; 2],
+}
+
+// A recording in FCEUX's FM2 text format. FamEmu's own header keys (romSha1,
+// famemu*) are skipped by FCEUX, FCEUX movies without them play from power on
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Movie {
+    pub rom_filename: String,
+    // Empty for movies that don't say which ROM they were made with
+    pub rom_sha1: String,
+    // FCEUX's "base64:" MD5 of PRG-ROM + CHR-ROM and the movie's unique id,
+    // kept as text since FamEmu only writes them back out
+    pub rom_checksum: String,
+    pub guid: String,
+    pub pal: bool,
+    pub rerecords: u32,
+    pub comments: Vec<String>,
+    pub seed: u64,
+    pub ram_init: RamInit,
+    // FamEmu save state the movie starts from, power on if none
+    pub savestate: Option<Vec<u8>>,
+    // Battery-backed PRG-RAM at power on. Power on movies without it start
+    // with the RAM filled from `ram_init`, not from the player's .sav
+    pub prg_ram: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+    // Frame number and CRC32 of RAM after that many frames
+    pub ram_hashes: Vec<(usize, u32)>,
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE64.iter().position(|b| *b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+// The checksum FCEUX compares before playing a movie
+fn rom_checksum(rom: &Rom) -> String {
+    format!("base64:{}", base64_encode(&md5::digest(&[&rom.prg_rom[..], &rom.chr_rom[..]].concat())))
+}
+
+// Random, formatted like FCEUX's
+fn new_guid() -> String {
+    let bytes: [u8; 16] = rand::random();
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn ram_init_name(ram_init: RamInit) -> String {
+    match ram_init {
+        RamInit::Zeros => "zeros".to_string(),
+        RamInit::Ones => "ones".to_string(),
+        RamInit::Pattern => "pattern".to_string(),
+        RamInit::Random(seed) => format!("random:{}", seed),
+    }
+}
+
+fn parse_ram_init(text: &str) -> Option<RamInit> {
+    match text {
+        "zeros" => Some(RamInit::Zeros),
+        "ones" => Some(RamInit::Ones),
+        "pattern" => Some(RamInit::Pattern),
+        _ => text.strip_prefix("random:")?.parse().ok().map(RamInit::Random),
+    }
+}
+
+fn format_port(buttons: JoypadButtons) -> String {
+    BUTTON_CHARS.iter().enumerate()
+        .map(|(i, c)| if buttons.bits() & (0x80 >> i) != 0 { *c as char } else { '.' })
+        .collect()
+}
+
+fn parse_port(text: &str) -> Result<JoypadButtons, String> {
+    if text.is_empty() {
+        return Ok(JoypadButtons::empty());
+    }
+    if text.len() != 8 {
+        return Err(format!("Expected 8 buttons, got {:?}", text));
+    }
+
+    let bits = text.bytes().enumerate()
+        .filter(|(_, c)| *c != b'.' && *c != b' ')
+        .fold(0u8, |bits, (i, _)| bits | 0x80 >> i);
+
+    Ok(JoypadButtons::from_bits_retain(bits))
+}
+
+// CRC32 of CPU RAM and cartridge RAM
+pub fn ram_hash(nes: &Nes) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&crate::headless::dump_ram(nes.bus()));
+    hasher.update(nes.bus().prg_ram());
+    hasher.finalize()
+}
+
+impl Movie {
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "version 3").unwrap();
+        writeln!(out, "emuVersion 22020").unwrap();
+        writeln!(out, "rerecordCount {}", self.rerecords).unwrap();
+        writeln!(out, "palFlag {}", self.pal as u8).unwrap();
+        writeln!(out, "romFilename {}", self.rom_filename).unwrap();
+        if !self.rom_checksum.is_empty() {
+            writeln!(out, "romChecksum {}", self.rom_checksum).unwrap();
+        }
+        if !self.guid.is_empty() {
+            writeln!(out, "guid {}", self.guid).unwrap();
+        }
+        writeln!(out, "fourscore 0").unwrap();
+        writeln!(out, "microphone 0").unwrap();
+        writeln!(out, "port0 1").unwrap();
+        writeln!(out, "port1 1").unwrap();
+        writeln!(out, "port2 0").unwrap();
+        writeln!(out, "FDS 0").unwrap();
+        writeln!(out, "NewPPU 0").unwrap();
+        for comment in &self.comments {
+            writeln!(out, "comment {}", comment).unwrap();
+        }
+
+        if !self.rom_sha1.is_empty() {
+            writeln!(out, "romSha1 {}", self.rom_sha1).unwrap();
+        }
+        writeln!(out, "famemuSeed {}", self.seed).unwrap();
+        writeln!(out, "famemuRamInit {}", ram_init_name(self.ram_init)).unwrap();
+        if let Some(state) = &self.savestate {
+            writeln!(out, "famemuSavestate base64:{}", base64_encode(state)).unwrap();
+        }
+        if let Some(prg_ram) = &self.prg_ram {
+            writeln!(out, "famemuPrgRam base64:{}", base64_encode(prg_ram)).unwrap();
+        }
+        for (frame, hash) in &self.ram_hashes {
+            writeln!(out, "famemuRamHash {} {:08X}", frame, hash).unwrap();
+        }
+
+        for frame in &self.frames {
+            writeln!(out, "|{}|{}|{}||", frame.commands, format_port(frame.ports[0]), format_port(frame.ports[1])).unwrap();
+        }
+
+        out
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| MovieError::Parse { line: i + 1, message };
+            let line = line.trim_end_matches('\r');
+
+            if let Some(input) = line.strip_prefix('|') {
+                let fields: Vec<&str> = input.split('|').collect();
+                if fields.len() < 2 {
+                    return Err(error(format!("Broken input line {:?}", line)));
+                }
+
+                let commands = fields[0].parse().map_err(|_| error(format!("Invalid commands {:?}", fields[0])))?;
+                let port1 = fields.get(2).copied().unwrap_or("");
+                movie.frames.push(MovieFrame {
+                    commands,
+                    ports: [parse_port(fields[1]).map_err(error)?, parse_port(port1).map_err(error)?],
+                });
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let number = |value: &str| value.parse().map_err(|_| error(format!("Invalid {} {:?}", key, value)));
+
+            match key {
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => movie.rom_checksum = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "rerecordCount" => movie.rerecords = number(value)? as u32,
+                "palFlag" => movie.pal = value == "1",
+                "comment" => movie.comments.push(value.to_string()),
+                "savestate" => return Err(MovieError::ForeignSavestate),
+                "romSha1" => movie.rom_sha1 = value.to_uppercase(),
+                "famemuSeed" => movie.seed = number(value)?,
+                "famemuRamInit" => {
+                    movie.ram_init = parse_ram_init(value).ok_or_else(|| error(format!("Invalid RAM init {:?}", value)))?;
+                }
+                "famemuSavestate" => {
+                    let state = value.strip_prefix("base64:").and_then(base64_decode);
+                    movie.savestate = Some(state.ok_or_else(|| error("Broken save state".to_string()))?);
+                }
+                "famemuPrgRam" => {
+                    let prg_ram = value.strip_prefix("base64:").and_then(base64_decode);
+                    movie.prg_ram = Some(prg_ram.ok_or_else(|| error("Broken PRG-RAM".to_string()))?);
+                }
+                "famemuRamHash" => {
+                    let (frame, hash) = value.split_once(' ').ok_or_else(|| error("Expected frame and hash".to_string()))?;
+                    let hash = u32::from_str_radix(hash, 16).map_err(|_| error(format!("Invalid hash {:?}", hash)))?;
+                    movie.ram_hashes.push((number(frame)? as usize, hash));
+                }
+                // Other FCEUX keys don't change how FamEmu plays the movie
+                _ => {}
+            }
+        }
+
+        Ok(movie)
+    }
+}
+
+// Applies the frame's commands and sets the controllers
+fn apply_frame(nes: &mut Nes, frame: &MovieFrame) {
+    if frame.commands & HARD_RESET != 0 {
+        nes.power_on();
+    } else if frame.commands & SOFT_RESET != 0 {
+        nes.reset();
+    }
+
+    nes.set_input(0, frame.ports[0]);
+    nes.set_input(1, frame.ports[1]);
+}
+
+// Records the controllers once per frame. Call begin_frame before and
+// end_frame after every frame
+pub struct Recorder {
+    movie: Movie,
+    hash_interval: usize,
+    commands: u8,
+}
+
+impl Recorder {
+    // Starts from power on, or from the machine as it is if `from_state`
+    pub fn new(nes: &mut Nes, rom_filename: &str, from_state: bool, hash_interval: usize) -> Self {
+        if !from_state {
+            nes.power_on();
+        }
+
+        let movie = Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_sha1: nes.rom().sha1.clone(),
+            rom_checksum: rom_checksum(nes.rom()),
+            guid: new_guid(),
+            pal: nes.timing() == Timing::Pal,
+            seed: nes.seed(),
+            ram_init: nes.ram_init(),
+            savestate: from_state.then(|| savestate::save(nes)),
+            prg_ram: (!from_state && nes.bus().is_battery_backed()).then(|| nes.bus().prg_ram().to_vec()),
+            ..Movie::default()
+        };
+
+        Recorder { movie, hash_interval: hash_interval.max(1), commands: 0 }
+    }
+
+    // Soft reset at the start of the next frame
+    pub fn reset(&mut self) {
+        self.commands |= SOFT_RESET;
+    }
+
+    pub fn power_on(&mut self) {
+        self.commands |= HARD_RESET;
+    }
+
+    // Applies pending commands and records the controllers as they are now
+    pub fn begin_frame(&mut self, nes: &mut Nes) {
+        let frame = MovieFrame {
+            commands: std::mem::take(&mut self.commands),
+            ports: [nes.bus().buttons(0), nes.bus().buttons(1)],
+        };
+
+        apply_frame(nes, &frame);
+        self.movie.frames.push(frame);
+    }
+
+    pub fn end_frame(&mut self, nes: &Nes) {
+        let frame = self.movie.frames.len();
+        if frame.is_multiple_of(self.hash_interval) {
+            self.movie.ram_hashes.push((frame, ram_hash(nes)));
+        }
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// Drives the controllers from a movie and checks the recorded RAM hashes
+pub struct Player {
+    movie: Movie,
+    frame: usize,
+    next_hash: usize,
+}
+
+impl Player {
+    // Puts the machine in the state the movie starts from
+    pub fn new(movie: Movie, nes: &mut Nes) -> Result<Self, MovieError> {
+        // FCEUX movies only carry the MD5 romChecksum
+        if !movie.rom_sha1.is_empty() {
+            if movie.rom_sha1 != nes.rom().sha1 {
+                return Err(MovieError::RomMismatch { expected: movie.rom_sha1, actual: nes.rom().sha1.clone() });
+            }
+        } else if !movie.rom_checksum.is_empty() {
+            let actual = rom_checksum(nes.rom());
+            if movie.rom_checksum != actual {
+                return Err(MovieError::RomMismatch { expected: movie.rom_checksum, actual });
+            }
+        }
+
+        if movie.pal != (nes.timing() == Timing::Pal) {
+            nes.set_timing(if movie.pal { Timing::Pal } else { Timing::Ntsc });
+        }
+        nes.set_seed(movie.seed);
+        nes.set_ram_init(movie.ram_init);
+        nes.power_on();
+
+        if let Some(state) = &movie.savestate {
+            savestate::load(nes, state)?;
+        } else if nes.bus().is_battery_backed() {
+            match &movie.prg_ram {
+                Some(prg_ram) => nes.bus_mut().load_prg_ram(prg_ram),
+                None => nes.bus_mut().init_prg_ram(),
+            }
+        }
+
+        Ok(Player { movie, frame: 0, next_hash: 0 })
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // Frames played so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    // Returns false once the movie is over
+    pub fn begin_frame(&mut self, nes: &mut Nes) -> bool {
+        match self.movie.frames.get(self.frame) {
+            Some(frame) => {
+                apply_frame(nes, frame);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn end_frame(&mut self, nes: &Nes) -> Result<(), Desync> {
+        self.frame += 1;
+
+        while let Some((frame, expected)) = self.movie.ram_hashes.get(self.next_hash).copied() {
+            if frame > self.frame {
+                break;
+            }
+            self.next_hash += 1;
+
+            let actual = ram_hash(nes);
+            if frame == self.frame && actual != expected {
+                return Err(Desync { frame, expected, actual });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::mem::Mem;
+
+    fn snake() -> Nes {
+        let mut nes = crate::nes::test_util::load("snake.nes");
+        nes.set_snake_io(true);
+        nes
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let movie = Movie {
+            rom_filename: "snake".to_string(),
+            rom_sha1: "2942508AC0DBF9EADC3B1486FA276C3C368FD631".to_string(),
+            rom_checksum: "base64:jfu3xyw5jI/LuXyXl3G5lQ==".to_string(),
+            guid: "0F3A74E1-52C6-4D39-B1D0-9E6E2F8A4C07".to_string(),
+            seed: 7,
+            ram_init: RamInit::Random(3),
+            savestate: Some(vec![1, 2, 3, 4, 5]),
+            prg_ram: Some(vec![0xFF; 16]),
+            frames: vec![
+                MovieFrame { commands: HARD_RESET, ports: [JoypadButtons::empty(); 2] },
+                MovieFrame { commands: 0, ports: [JoypadButtons::RIGHT | JoypadButtons::A, JoypadButtons::START] },
+            ],
+            ram_hashes: vec![(2, 0xDEADBEEF)],
+            ..Movie::default()
+        };
+
+        let text = movie.to_fm2();
+        assert!(text.contains("|0|R......A|....T...||"));
+        assert_eq!(Movie::from_fm2(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_fceux_movie() {
+        let text = "version 3\nromFilename game\npalFlag 0\nport0 1\n|0|........|||\n|1|...U....|||\n";
+        let movie = Movie::from_fm2(text).unwrap();
+
+        assert_eq!(movie.frames.len(), 2);
+        assert_eq!(movie.frames[1].commands, SOFT_RESET);
+        assert_eq!(movie.frames[1].ports[0], JoypadButtons::UP);
+    }
+
+    #[test]
+    fn test_playback_detects_desync() {
+        let mut nes = snake();
+        let mut recorder = Recorder::new(&mut nes, "snake", false, 1);
+
+        for i in 0..3 {
+            nes.set_input(0, if i == 1 { JoypadButtons::DOWN } else { JoypadButtons::empty() });
+            recorder.begin_frame(&mut nes);
+            nes.run_frame();
+            recorder.end_frame(&nes);
+        }
+        let movie = Movie::from_fm2(&recorder.finish().to_fm2()).unwrap();
+
+        let mut player = Player::new(movie.clone(), &mut nes).unwrap();
+        while player.begin_frame(&mut nes) {
+            nes.run_frame();
+            player.end_frame(&nes).unwrap();
+        }
+        assert!(player.is_finished());
+
+        let foreign = Movie {
+            rom_sha1: String::new(),
+            rom_checksum: "base64:AAAAAAAAAAAAAAAAAAAAAA==".to_string(),
+            ..movie.clone()
+        };
+        assert!(matches!(Player::new(foreign, &mut nes), Err(MovieError::RomMismatch { .. })));
+        let fceux = Movie { rom_sha1: String::new(), ..movie.clone() };
+        assert!(Player::new(fceux, &mut nes).is_ok());
+
+        let mut player = Player::new(movie, &mut nes).unwrap();
+        player.begin_frame(&mut nes);
+        nes.cpu_mut().mem_write(0x07F0, 0x42);
+        nes.run_frame();
+        assert_eq!(player.end_frame(&nes).map_err(|desync| desync.frame), Err(1));
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/snake.nes");
+        let mut rom = Rom::new(&std::fs::read(path).unwrap()).unwrap();
+        rom.battery = true;
+        rom.prg_ram_size = 0;
+        rom.prg_nvram_size = 0x2000;
+        let mut nes = Nes::new(rom);
+
+        // The recording starts from one .sav, playback from another
+        nes.bus_mut().load_prg_ram(&[0x11; 0x2000]);
+        let mut recorder = Recorder::new(&mut nes, "snake", false, 1);
+        recorder.begin_frame(&mut nes);
+        nes.run_frame();
+        recorder.end_frame(&nes);
+        let movie = Movie::from_fm2(&recorder.finish().to_fm2()).unwrap();
+        assert_eq!(movie.prg_ram, Some(vec![0x11; 0x2000]));
+
+        nes.bus_mut().load_prg_ram(&[0x22; 0x2000]);
+        let mut player = Player::new(movie.clone(), &mut nes).unwrap();
+        player.begin_frame(&mut nes);
+        nes.run_frame();
+        assert_eq!(player.end_frame(&nes), Ok(()));
+
+        // FCEUX movies don't carry PRG-RAM, it starts from `ram_init`
+        let movie = Movie { prg_ram: None, ..movie };
+        nes.bus_mut().load_prg_ram(&[0x22; 0x2000]);
+        Player::new(movie, &mut nes).unwrap();
+        assert_eq!(nes.bus().prg_ram(), &[0; 0x2000][..]);
+    }
+}
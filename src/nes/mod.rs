@@ -0,0 +1,393 @@
+use crate::bus::joypad::JoypadButtons;
+use crate::bus::{Bus, RamInit};
+use crate::cdl::CodeDataLog;
+use crate::cpu::cpu::CPU;
+use crate::cpu::mem::Mem;
+use crate::profiler::Profiler;
+use crate::rom::{Rom, Timing};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::screen::{self, Frame};
+
+pub const SAMPLE_RATE: u32 = 44100;
+const DOTS_PER_SCANLINE: usize = 341;
+
+// The snake demo reads a random number from $FE and the last pressed key from $FF
+const SNAKE_RANDOM: u16 = 0xFE;
+const SNAKE_KEY: u16 = 0xFF;
+
+#[cfg(test)]
+pub(crate) mod test_util;
+
+// The whole console: CPU, bus with the cartridge and controllers, and the
+// frame and audio produced by the last run_frame
+pub struct Nes {
+    cpu: CPU,
+    timing: Timing,
+    frame: Frame,
+    frame_count: usize,
+    audio: Vec<i16>,
+    // Cycle count the audio buffer was last filled up to
+    audio_cycles: usize,
+    halted: bool,
+    // Off unless asked for with set_snake_io
+    snake_io: bool,
+    // Seeds `rng` on power on, movies store it to replay the same numbers
+    seed: u64,
+    // xorshift64 state for the snake's random numbers, kept here so save
+    // states can restore it
+    rng: u64,
+    cdl: Option<CodeDataLog>,
+    profiler: Option<Profiler>,
+}
+
+impl Nes {
+    pub fn new(rom: Rom) -> Self {
+        let timing = rom.timing;
+
+        let mut nes = Nes {
+            cpu: CPU::new(Bus::new(rom)),
+            timing,
+            frame: [0; screen::FRAME_SIZE],
+            frame_count: 0,
+            audio: vec![],
+            audio_cycles: 0,
+            halted: false,
+            snake_io: false,
+            seed: rand::random(),
+            rng: 1,
+            cdl: None,
+            profiler: None,
+        };
+        nes.power_on();
+
+        nes
+    }
+
+    pub fn power_on(&mut self) {
+        self.cpu.bus.power_on();
+        self.cpu.power_on();
+        // xorshift gets stuck on zero
+        self.rng = self.seed | 1;
+
+        self.frame = [0; screen::FRAME_SIZE];
+        self.frame_count = 0;
+        self.audio.clear();
+        self.audio_cycles = 0;
+        self.halted = false;
+
+        if let Some(cdl) = &mut self.cdl {
+            cdl.log_reset(&self.cpu.bus);
+        }
+    }
+
+    // Soft reset, registers other than SP and P and all memory are kept
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.halted = false;
+
+        if let Some(cdl) = &mut self.cdl {
+            cdl.log_reset(&self.cpu.bus);
+        }
+    }
+
+    // Starts or stops code/data logging. Marks add up from what `cdl` already holds
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) {
+        self.cdl = cdl;
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    // Starts or stops profiling, the call stack starts at the current PC
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // The snake demo's I/O: a random number at $FE before every instruction
+    // and the last direction pressed on controller 1 as a WASD key at $FF.
+    // Those addresses are plain RAM to anything else, so it's opt-in
+    pub fn set_snake_io(&mut self, snake_io: bool) {
+        self.snake_io = snake_io;
+    }
+
+    pub fn snake_io(&self) -> bool {
+        self.snake_io
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Takes effect on the next power_on
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn ram_init(&self) -> RamInit {
+        self.cpu.bus.ram_init()
+    }
+
+    // Takes effect on the next power_on
+    pub fn set_ram_init(&mut self, ram_init: RamInit) {
+        self.cpu.bus.set_ram_init(ram_init);
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    // Overrides the region from the ROM header
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    // Executes one instruction, returns false once the CPU is halted on BRK
+    pub fn step(&mut self) -> bool {
+        if self.halted {
+            return false;
+        }
+
+        if self.snake_io {
+            let random = self.next_random() % 15 + 1;
+            self.cpu.mem_write(SNAKE_RANDOM, random as u8);
+        }
+
+        if let Some(cdl) = &mut self.cdl {
+            cdl.log_instruction(&self.cpu);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.step(&self.cpu, self.frame_count);
+        }
+
+        self.halted = !self.cpu.step();
+        !self.halted
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    // Runs to the end of the current frame. `callback` is called before every
+    // instruction and can stop the frame early by returning false. Returns
+    // false if the frame was cut short by the callback or a halted CPU
+    pub fn run_frame_with<F>(&mut self, mut callback: F) -> bool
+    where
+        F: FnMut(&mut Nes) -> bool
+    {
+        let frame_end = (self.frame_count + 1) * self.timing.cpu_cycles_per_frame();
+        let mut finished = true;
+
+        while self.cpu.bus.cycles() < frame_end {
+            if !callback(self) || !self.step() {
+                finished = false;
+                break;
+            }
+        }
+
+        if finished {
+            self.frame_count += 1;
+        }
+
+        screen::read_screen_state(&self.cpu.bus, &mut self.frame);
+        self.fill_audio();
+
+        finished
+    }
+
+    pub fn run_frame(&mut self) -> &Frame {
+        self.run_frame_with(|_| true);
+        &self.frame
+    }
+
+    // There's no APU yet, so every frame produces silence of its length
+    fn fill_audio(&mut self) {
+        let rate = self.timing.cpu_clock_rate() as u64;
+        let samples_at = |cycles: usize| cycles as u64 * SAMPLE_RATE as u64 / rate;
+
+        let cycles = self.cpu.bus.cycles();
+        let samples = samples_at(cycles).saturating_sub(samples_at(self.audio_cycles));
+
+        self.audio.clear();
+        self.audio.resize(samples as usize, 0);
+        self.audio_cycles = cycles;
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    // Scanline and dot the PPU is on. There's no PPU yet, so both follow
+    // from the CPU cycle count with the first scanline starting at power on
+    pub fn ppu_position(&self) -> (usize, usize) {
+        let dots = self.timing.ppu_dots(self.cpu.bus.cycles());
+        ((dots / DOTS_PER_SCANLINE) % self.timing.scanlines(), dots % DOTS_PER_SCANLINE)
+    }
+
+    // Frames completed since power on
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    // Mono samples at SAMPLE_RATE produced by the last frame
+    pub fn audio_samples(&self) -> &[i16] {
+        &self.audio
+    }
+
+    // Buttons held on controller `port`, 0 or 1
+    pub fn set_input(&mut self, port: usize, buttons: JoypadButtons) {
+        let pressed = buttons - self.cpu.bus.buttons(port);
+        self.cpu.bus.set_buttons(port, buttons);
+
+        if self.snake_io && port == 0 {
+            let keys = [
+                (JoypadButtons::UP, b'w'),
+                (JoypadButtons::LEFT, b'a'),
+                (JoypadButtons::DOWN, b's'),
+                (JoypadButtons::RIGHT, b'd'),
+            ];
+
+            for (button, key) in keys {
+                if pressed.contains(button) {
+                    self.cpu.mem_write(SNAKE_KEY, key);
+                }
+            }
+        }
+    }
+
+    // Sections in the order savestate::load expects them
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"CPU ", |state| self.cpu.save_state(state));
+        state.section(b"BUS ", |state| self.cpu.bus.save_state(state));
+        state.section(b"NES ", |state| {
+            state.u64(self.frame_count as u64);
+            state.u64(self.audio_cycles as u64);
+            state.bool(self.halted);
+            state.u64(self.seed);
+            state.u64(self.rng);
+        });
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"CPU ", "CPU", |state| self.cpu.load_state(state))?;
+        state.section(b"BUS ", "bus", |state| self.cpu.bus.load_state(state))?;
+        state.section(b"NES ", "console", |state| {
+            self.frame_count = state.u64()? as usize;
+            self.audio_cycles = state.u64()? as usize;
+            self.halted = state.bool()?;
+            self.seed = state.u64()?;
+            self.rng = state.u64()?;
+            Ok(())
+        })?;
+
+        screen::read_screen_state(&self.cpu.bus, &mut self.frame);
+        self.audio.clear();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.forget_stack();
+        }
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.cpu.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.cpu.bus
+    }
+
+    pub fn rom(&self) -> &Rom {
+        self.cpu.bus.rom()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::watch::Access;
+    use test_util::load;
+
+    #[test]
+    fn test_run_frame() {
+        // nestest spins waiting for the PPU, so it never halts
+        let mut nes = load("nestest.nes");
+        nes.run_frame();
+        nes.run_frame();
+
+        assert_eq!(nes.frame_count(), 2);
+        assert!(nes.bus().cycles() >= 2 * Timing::Ntsc.cpu_cycles_per_frame());
+        // 29781 cycles at 1789773 Hz is 733.8 samples
+        assert!((733..=735).contains(&nes.audio_samples().len()));
+    }
+
+    #[test]
+    fn test_snake_input() {
+        let mut nes = load("snake.nes");
+        nes.set_input(0, JoypadButtons::RIGHT);
+        assert_eq!(nes.cpu().mem_read(SNAKE_KEY), 0);
+
+        nes.set_snake_io(true);
+        nes.set_input(0, JoypadButtons::LEFT);
+        assert_eq!(nes.cpu().mem_read(SNAKE_KEY), b'a');
+        assert_eq!(nes.bus().buttons(0), JoypadButtons::LEFT);
+
+        nes.power_on();
+        assert_eq!(nes.cpu().mem_read(SNAKE_KEY), 0);
+        assert_eq!(nes.frame_count(), 0);
+    }
+
+    #[test]
+    fn test_reset_keeps_state() {
+        let mut nes = load("snake.nes");
+        nes.set_ram_init(RamInit::Ones);
+        nes.power_on();
+        assert_eq!(nes.cpu().mem_read(0x0010), 0xFF);
+
+        nes.cpu_mut().register_a = 0x42;
+        nes.cpu_mut().mem_write(0x0010, 0x24);
+        nes.reset();
+
+        assert_eq!(nes.cpu().register_a, 0x42);
+        assert_eq!(nes.cpu().stack_pointer, 0xFA);
+        assert!(nes.cpu().status.contains(crate::CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(nes.cpu().mem_read(0x0010), 0x24);
+    }
+
+    #[test]
+    fn test_read_watchpoints_skip_fetches() {
+        let mut nes = test_util::nestest();
+        test_util::load_program(&mut nes, "JMP $0600");
+        let watchpoints = nes.bus_mut().watchpoints_mut();
+        watchpoints.add(0x05F0, 0x05F0, Access::READ, None);
+        watchpoints.add(0x0600, 0x0602, Access::READ, None);
+
+        // Drawing the frame peeks the screen at $0200-$05FF
+        nes.run_frame();
+        assert_eq!(nes.bus().watchpoints().take_hit(), None);
+
+        test_util::load_program(&mut nes, "LDA #$01\nLDA $05F0");
+        nes.step();
+        assert_eq!(nes.bus().watchpoints().take_hit(), None);
+        nes.step();
+        assert_eq!(nes.bus().watchpoints().take_hit().map(|hit| hit.addr), Some(0x05F0));
+    }
+}
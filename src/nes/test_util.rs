@@ -0,0 +1,21 @@
+// Consoles for the tests of the modules built on Nes
+use crate::cpu::asm::assemble;
+use crate::cpu::cpu::PROGRAM_START;
+use crate::nes::Nes;
+use crate::rom::Rom;
+
+// A console with one of the ROMs from the repository root
+pub fn load(name: &str) -> Nes {
+    let path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name);
+    Nes::new(Rom::new(&std::fs::read(path).unwrap()).unwrap())
+}
+
+pub fn nestest() -> Nes {
+    load("nestest.nes")
+}
+
+// Assembles `source` into RAM at PROGRAM_START and points PC at it
+pub fn load_program(nes: &mut Nes, source: &str) {
+    nes.cpu_mut().load(assemble(source, PROGRAM_START).unwrap());
+    nes.cpu_mut().program_counter = PROGRAM_START;
+}
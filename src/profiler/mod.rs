@@ -0,0 +1,251 @@
+use crate::bus::Bus;
+use crate::cpu::cpu::{CallEvent, CPU, RESET_VECTOR};
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Cycles spent in one routine. Inclusive counts its callees too, recursion
+// only once
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct RoutineStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+    // Most inclusive cycles in a single frame, session stats only
+    pub peak_frame: u64,
+}
+
+// Follows JSR/RTS, interrupts and RTI to keep a call stack and charges every
+// cycle to the routines on it. Routines are known by their entry address in
+// CPU space, so with bank switching routines from different banks that share
+// an address are counted together
+#[derive(Debug, Default)]
+pub struct Profiler {
+    // Entry addresses of the routines on the call stack, the key into `folded`
+    path: Vec<u16>,
+    // The stack pointer right after each of them was entered
+    entry_sp: Vec<u8>,
+    last_cycles: usize,
+    frame_count: usize,
+    frames: usize,
+    session: HashMap<u16, RoutineStats>,
+    frame: HashMap<u16, RoutineStats>,
+    last_frame: HashMap<u16, RoutineStats>,
+    folded: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    // Call before every instruction, like CodeDataLog::log_instruction. The
+    // cycles since the last call go to the stack the previous instruction ran
+    // on, then the stack follows whatever that instruction did
+    pub fn step(&mut self, cpu: &CPU, frame_count: usize) {
+        let cycles = cpu.bus.cycles();
+        if self.path.is_empty() {
+            self.push(cpu.program_counter, cpu.stack_pointer);
+            self.last_cycles = cycles;
+            self.frame_count = frame_count;
+            return;
+        }
+        // Called again without running anything, e.g. after a breakpoint
+        if cycles == self.last_cycles {
+            return;
+        }
+
+        // Cycles start over on power on
+        let elapsed = cycles.saturating_sub(self.last_cycles) as u64;
+        self.last_cycles = cycles;
+        self.charge(elapsed);
+
+        if frame_count != self.frame_count {
+            self.end_frame();
+            self.frame_count = frame_count;
+        }
+
+        match cpu.call_event() {
+            Some(CallEvent::Call { to, .. }) => self.push(to, cpu.stack_pointer),
+            Some(CallEvent::Interrupt { vector: RESET_VECTOR, to, .. }) => {
+                self.path.clear();
+                self.entry_sp.clear();
+                self.push(to, cpu.stack_pointer);
+            }
+            Some(CallEvent::Interrupt { to, .. }) => self.push(to, cpu.stack_pointer),
+            // Anything entered below the new stack pointer has returned. A
+            // routine that pushes an address and RTSes to it stays where it
+            // is, that's a jump
+            Some(CallEvent::Return { .. } | CallEvent::ReturnFromInterrupt { .. }) => {
+                while self.path.len() > 1 && self.entry_sp[self.entry_sp.len() - 1] < cpu.stack_pointer {
+                    self.path.pop();
+                    self.entry_sp.pop();
+                }
+            }
+            None => {}
+        }
+    }
+
+    // The stack no longer matches the CPU after a save state is loaded. The
+    // next step starts a new one at PC, the stats are kept
+    pub fn forget_stack(&mut self) {
+        self.path.clear();
+        self.entry_sp.clear();
+    }
+
+    fn push(&mut self, routine: u16, sp: u8) {
+        self.path.push(routine);
+        self.entry_sp.push(sp);
+        self.session.entry(routine).or_default().calls += 1;
+        self.frame.entry(routine).or_default().calls += 1;
+    }
+
+    fn charge(&mut self, cycles: u64) {
+        for (i, routine) in self.path.iter().enumerate() {
+            if self.path[..i].contains(routine) {
+                continue;
+            }
+            self.session.entry(*routine).or_default().inclusive += cycles;
+            self.frame.entry(*routine).or_default().inclusive += cycles;
+        }
+
+        let top = self.path[self.path.len() - 1];
+        self.session.entry(top).or_default().exclusive += cycles;
+        self.frame.entry(top).or_default().exclusive += cycles;
+
+        match self.folded.get_mut(&self.path[..]) {
+            Some(total) => *total += cycles,
+            None => {
+                self.folded.insert(self.path.clone(), cycles);
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        for (routine, stats) in &self.frame {
+            let session = self.session.entry(*routine).or_default();
+            session.peak_frame = session.peak_frame.max(stats.inclusive);
+        }
+        self.last_frame = std::mem::take(&mut self.frame);
+        self.frames += 1;
+    }
+
+    // Frames finished while profiling
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    // Entry addresses from the outermost routine in
+    pub fn call_stack(&self) -> &[u16] {
+        &self.path
+    }
+
+    // Everything so far, most inclusive cycles first
+    pub fn session(&self) -> Vec<(u16, RoutineStats)> {
+        sorted(&self.session)
+    }
+
+    // The last finished frame, most inclusive cycles first
+    pub fn last_frame(&self) -> Vec<(u16, RoutineStats)> {
+        sorted(&self.last_frame)
+    }
+
+    // Session table with per frame averages, at most `limit` routines
+    pub fn report(&self, bus: &Bus, limit: usize) -> String {
+        let mut report = format!(
+            "{:<24} {:>8} {:>12} {:>12} {:>10} {:>10}\n",
+            "Routine", "Calls", "Inclusive", "Exclusive", "Incl/frame", "Peak frame"
+        );
+        let frames = self.frames.max(1) as u64;
+
+        for (routine, stats) in self.session().into_iter().take(limit) {
+            report += &format!(
+                "{:<24} {:>8} {:>12} {:>12} {:>10} {:>10}\n",
+                routine_name(bus, routine), stats.calls, stats.inclusive, stats.exclusive,
+                stats.inclusive / frames, stats.peak_frame
+            );
+        }
+        report
+    }
+
+    // Brendan Gregg's folded stacks, "outer;inner cycles" per line, for
+    // flamegraph.pl, inferno or speedscope
+    pub fn write_folded<W: Write>(&self, bus: &Bus, out: &mut W) -> io::Result<()> {
+        let mut stacks: Vec<(String, u64)> = self.folded.iter()
+            .map(|(path, cycles)| {
+                let names: Vec<String> = path.iter().map(|routine| routine_name(bus, *routine)).collect();
+                (names.join(";"), *cycles)
+            })
+            .collect();
+        stacks.sort();
+
+        for (stack, cycles) in stacks {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+fn sorted(stats: &HashMap<u16, RoutineStats>) -> Vec<(u16, RoutineStats)> {
+    let mut sorted: Vec<(u16, RoutineStats)> = stats.iter().map(|(routine, stats)| (*routine, *stats)).collect();
+    sorted.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+    sorted
+}
+
+// The symbol at `routine` or its address
+pub fn routine_name(bus: &Bus, routine: u16) -> String {
+    bus.label(routine).map(str::to_string).unwrap_or_else(|| format!("${:04X}", routine))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::test_util::{load_program, nestest};
+
+    #[test]
+    fn test_profile_calls() {
+        let mut nes = nestest();
+
+        // The RTS at $0611 returns to the pushed address, a jump within inner
+        let source = "
+            JSR outer
+            BRK
+            outer: JSR inner
+            JSR inner
+            RTS
+            inner: LDA #>done
+            PHA
+            LDA #<(done - 1)
+            PHA
+            RTS
+            done: RTS
+        ";
+        load_program(&mut nes, source);
+        nes.set_profiler(Some(Profiler::new()));
+        let mut inner = None;
+        while nes.step() {
+            if nes.cpu().program_counter == 0x060B && inner.is_none() {
+                inner = Some(crate::savestate::save(&nes));
+            }
+        }
+        let profiler = nes.profiler().unwrap();
+
+        // inner: LDA 2 + PHA 3 + LDA 2 + PHA 3 + RTS 6 + RTS 6, twice
+        let stats: HashMap<u16, RoutineStats> = profiler.session().into_iter().collect();
+        assert_eq!(stats[&0x060B], RoutineStats { calls: 2, inclusive: 44, exclusive: 44, peak_frame: 0 });
+        // JSR 6 + JSR 6 + RTS 6 of its own
+        assert_eq!(stats[&0x0604], RoutineStats { calls: 1, inclusive: 62, exclusive: 18, peak_frame: 0 });
+        // The JSR into outer, BRK halts before its cycles are charged
+        assert_eq!(stats[&0x0600].exclusive, 6);
+        assert_eq!(profiler.call_stack(), &[0x0600]);
+
+        let mut folded = vec![];
+        profiler.write_folded(nes.bus(), &mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "$0600 6\n$0600;$0604 18\n$0600;$0604;$060B 44\n");
+
+        // Back inside inner, which the stack can't know it was called from
+        crate::savestate::load(&mut nes, &inner.unwrap()).unwrap();
+        nes.step();
+        assert_eq!(nes.profiler().unwrap().call_stack(), &[0x060B]);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::nes::Nes;
+use crate::savestate;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+// Ring buffer of save states taken every `interval` frames. Only the newest
+// state is kept whole, every older one is stored as the deflated XOR against
+// the state after it, which is mostly zeros. The newest state counts against
+// `budget` too: the oldest deltas are dropped once the buffer grows past it,
+// and a budget too small for one full state keeps nothing
+pub struct Rewind {
+    interval: usize,
+    budget: usize,
+    // Frame number and full state of the newest snapshot
+    newest: Option<(usize, Vec<u8>)>,
+    // Oldest first, the back one turns `newest` into the snapshot before it
+    deltas: VecDeque<(usize, Vec<u8>)>,
+    used: usize,
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// An empty or broken delta comes back empty
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    match DeflateDecoder::new(data).read_to_end(&mut out) {
+        Ok(_) => out,
+        Err(_) => vec![],
+    }
+}
+
+impl Rewind {
+    pub fn new(interval: usize, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Number of snapshots that can be stepped back to
+    pub fn len(&self) -> usize {
+        self.newest.iter().count() + self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    // Bytes held by the buffer
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Call after every frame, takes a snapshot every `interval` frames
+    pub fn record(&mut self, nes: &Nes) {
+        let frame = nes.frame_count();
+        if !frame.is_multiple_of(self.interval) || self.newest.as_ref().is_some_and(|(newest, _)| *newest == frame) {
+            return;
+        }
+
+        let state = savestate::save(nes);
+        self.used += state.len();
+
+        if let Some((newest_frame, newest)) = self.newest.take() {
+            self.used -= newest.len();
+            // States of one ROM all have the same size, the XOR needs that
+            let delta = if newest.len() == state.len() { compress(&xor(&newest, &state)) } else { vec![] };
+            self.used += delta.len();
+            self.deltas.push_back((newest_frame, delta));
+        }
+        self.newest = Some((frame, state));
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some((_, delta)) => self.used -= delta.len(),
+                None => {
+                    self.newest = None;
+                    self.used = 0;
+                }
+            }
+        }
+    }
+
+    // Removes the newest snapshot and rebuilds the one before it
+    fn pop(&mut self) -> Option<(usize, Vec<u8>)> {
+        let (frame, state) = self.newest.take()?;
+        self.used -= state.len();
+
+        if let Some((previous_frame, delta)) = self.deltas.pop_back() {
+            self.used -= delta.len();
+
+            let delta = decompress(&delta);
+            if delta.len() == state.len() {
+                let previous = xor(&state, &delta);
+                self.used += previous.len();
+                self.newest = Some((previous_frame, previous));
+            } else {
+                // The chain is broken past this point
+                self.deltas.clear();
+                self.used = 0;
+            }
+        }
+
+        Some((frame, state))
+    }
+
+    // Loads the newest snapshot older than the current frame, returns false
+    // when there's nothing left to go back to
+    pub fn step_back(&mut self, nes: &mut Nes) -> bool {
+        while let Some((frame, state)) = self.pop() {
+            if frame < nes.frame_count() || self.is_empty() {
+                return savestate::load(nes, &state).is_ok();
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::test_util::nestest;
+
+    #[test]
+    fn test_step_back() {
+        let mut nes = nestest();
+        let mut rewind = Rewind::new(2, 1 << 20);
+        let mut cycles = vec![];
+
+        for _ in 0..6 {
+            nes.run_frame();
+            rewind.record(&nes);
+            cycles.push(nes.bus().cycles());
+        }
+        assert_eq!(rewind.len(), 3);
+
+        // Frame 6 was just recorded, so the first step goes to frame 4
+        assert!(rewind.step_back(&mut nes));
+        assert_eq!(nes.frame_count(), 4);
+        assert_eq!(nes.bus().cycles(), cycles[3]);
+
+        assert!(rewind.step_back(&mut nes));
+        assert_eq!(nes.frame_count(), 2);
+        assert_eq!(nes.bus().cycles(), cycles[1]);
+        assert!(!rewind.step_back(&mut nes));
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut nes = nestest();
+        let state_size = savestate::save(&nes).len();
+        let mut rewind = Rewind::new(1, state_size + 200);
+
+        for _ in 0..50 {
+            nes.run_frame();
+            rewind.record(&nes);
+        }
+
+        assert!(rewind.used() <= state_size + 200);
+        assert!(rewind.len() > 1 && rewind.len() < 50);
+
+        let mut rewind = Rewind::new(1, state_size - 1);
+        nes.run_frame();
+        rewind.record(&nes);
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.used(), 0);
+    }
+}
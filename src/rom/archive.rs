@@ -0,0 +1,169 @@
+use crate::rom::MAX_ROM_SIZE;
+
+use flate2::read::MultiGzDecoder;
+
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(std::io::Error),
+    Zip(zip::result::ZipError),
+    // Archive has no .nes file
+    NoRomInArchive,
+    // Unpacks to more than MAX_ROM_SIZE bytes
+    TooLarge { max: usize },
+    // Requested entry isn't in the archive, lists the ROMs that are
+    EntryNotFound { entry: String, available: Vec<String> },
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::Io(err) => write!(f, "{}", err),
+            ArchiveError::Zip(err) => write!(f, "Broken zip archive: {}", err),
+            ArchiveError::NoRomInArchive => write!(f, "Archive doesn't contain a .nes file"),
+            ArchiveError::TooLarge { max } => write!(f, "Unpacks to more than {} bytes", max),
+            ArchiveError::EntryNotFound { entry, available } => write!(
+                f, "Archive has no {}, available ROMs: {}", entry, available.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(err: std::io::Error) -> Self {
+        ArchiveError::Io(err)
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(err: zip::result::ZipError) -> Self {
+        ArchiveError::Zip(err)
+    }
+}
+
+const ZIP_TAG: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+const GZIP_TAG: [u8; 2] = [0x1F, 0x8B];
+// UNIF and FDS images aren't supported, so only iNES files count as ROMs
+const ROM_EXTENSION: &str = "nes";
+
+pub struct Image {
+    pub data: Vec<u8>,
+    // ROM files in the zip archive the image came from, in archive order.
+    // Empty for plain and gzip files
+    pub roms: Vec<String>,
+}
+
+// Reads a ROM image from a plain, gzip or zip file. For zip archives `entry`
+// picks the file inside, otherwise the first ROM in the archive is used
+pub fn read_image(path: &Path, entry: Option<&str>) -> Result<Image, ArchiveError> {
+    let data = fs::read(path)?;
+
+    if data.starts_with(&ZIP_TAG) {
+        read_zip(data, entry)
+    } else if data.starts_with(&GZIP_TAG) {
+        Ok(Image { data: read_limited(MultiGzDecoder::new(&data[..]))?, roms: vec![] })
+    } else {
+        Ok(Image { data, roms: vec![] })
+    }
+}
+
+// Reads at most MAX_ROM_SIZE bytes, sizes claimed by archive headers aren't trusted
+fn read_limited<R: Read>(reader: R) -> Result<Vec<u8>, ArchiveError> {
+    let mut data = vec![];
+    reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut data)?;
+
+    if data.len() > MAX_ROM_SIZE {
+        return Err(ArchiveError::TooLarge { max: MAX_ROM_SIZE });
+    }
+    Ok(data)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(ROM_EXTENSION))
+}
+
+fn read_zip(data: Vec<u8>, entry: Option<&str>) -> Result<Image, ArchiveError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+
+    let mut roms = vec![];
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if file.is_file() && is_rom_name(file.name()) {
+            roms.push((i, file.name().to_string()));
+        }
+    }
+
+    let index = match entry {
+        Some(entry) => roms.iter()
+            .find(|(_, name)| name == entry)
+            .map(|(i, _)| *i)
+            .ok_or_else(|| ArchiveError::EntryNotFound {
+                entry: entry.to_string(),
+                available: roms.iter().map(|(_, name)| name.clone()).collect(),
+            })?,
+        None => roms.first().map(|(i, _)| *i).ok_or(ArchiveError::NoRomInArchive)?,
+    };
+
+    let data = read_limited(archive.by_index(index)?)?;
+
+    Ok(Image { data, roms: roms.into_iter().map(|(_, name)| name).collect() })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("famemu-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_gzip() {
+        let path = temp_path("game.nes.gz");
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"NES\x1Agame").unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        assert_eq!(read_image(&path, None).unwrap().data, b"NES\x1Agame");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_zip_picks_rom() {
+        let path = temp_path("games.zip");
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+
+        writer.start_file("readme.txt", options).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.add_directory("dir.nes/", options).unwrap();
+        writer.start_file("disk.fds", options).unwrap();
+        writer.write_all(b"disk").unwrap();
+        writer.start_file("first.nes", options).unwrap();
+        writer.write_all(b"first").unwrap();
+        writer.start_file("second.nes", options).unwrap();
+        writer.write_all(b"second").unwrap();
+        fs::write(&path, writer.finish().unwrap().into_inner()).unwrap();
+
+        let image = read_image(&path, None).unwrap();
+        assert_eq!(image.data, b"first");
+        assert_eq!(image.roms, vec!["first.nes", "second.nes"]);
+        assert_eq!(read_image(&path, Some("second.nes")).unwrap().data, b"second");
+        assert!(matches!(
+            read_image(&path, Some("readme.txt")),
+            Err(ArchiveError::EntryNotFound { .. })
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use crate::rom::{Mirroring, Timing};
+
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct GameInfo {
+    pub title: String,
+    pub region: Timing,
+    pub mapper: u16,
+    pub submapper: u8,
+    // None when the mapper switches mirroring, the header bit means nothing then
+    pub mirroring: Option<Mirroring>,
+    pub battery: bool,
+    // Uppercase hex, None if the entry only has a CRC32
+    pub sha1: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GameDbError {
+    Malformed { line: usize, text: String },
+}
+
+impl std::fmt::Display for GameDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameDbError::Malformed { line, text } => write!(f, "Malformed game database line {}: {}", line, text),
+        }
+    }
+}
+
+impl std::error::Error for GameDbError {}
+
+// Converted from the NES 2.0 XML database by tools/nes20db_to_gamedb.py
+const GENERATED_DB: &str = include_str!("gamedb.txt");
+// Hand-written entries for ROMs nes20db doesn't know, like the bundled ones
+const LOCAL_DB: &str = include_str!("gamedb_local.txt");
+
+lazy_static::lazy_static! {
+    // test_embedded_db fails on a malformed line, here one only loses that entry
+    pub static ref GAME_DB: HashMap<u32, GameInfo> = entries(GENERATED_DB)
+        .chain(entries(LOCAL_DB))
+        .filter_map(Result::ok)
+        .collect();
+}
+
+fn parse_line(line: &str) -> Option<(u32, GameInfo)> {
+    let fields: Vec<&str> = line.split(';').map(|f| f.trim()).collect();
+
+    if fields.len() != 8 {
+        return None;
+    }
+
+    let crc = u32::from_str_radix(fields[0], 16).ok()?;
+    let sha1 = if fields[1].is_empty() { None } else { Some(fields[1].to_uppercase()) };
+
+    let mirroring = match fields[4] {
+        "H" => Some(Mirroring::HORIZONTAL),
+        "V" => Some(Mirroring::VERTICAL),
+        "4" => Some(Mirroring::FOUR_SCREEN),
+        "M" => None,
+        _ => return None,
+    };
+
+    let region = match fields[6] {
+        "NTSC" => Timing::Ntsc,
+        "PAL" => Timing::Pal,
+        "World" => Timing::MultiRegion,
+        "Dendy" => Timing::Dendy,
+        _ => return None,
+    };
+
+    Some((crc, GameInfo {
+        title: fields[7].to_string(),
+        region,
+        mapper: fields[2].parse().ok()?,
+        submapper: fields[3].parse().ok()?,
+        mirroring,
+        battery: fields[5] == "1",
+        sha1,
+    }))
+}
+
+fn entries(db: &str) -> impl Iterator<Item = Result<(u32, GameInfo), GameDbError>> + '_ {
+    db.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            parse_line(line).ok_or_else(|| GameDbError::Malformed { line: number, text: line.to_string() })
+        })
+}
+
+pub fn parse(db: &str) -> Result<HashMap<u32, GameInfo>, GameDbError> {
+    entries(db).collect()
+}
+
+// Finds a game by checksums of its PRG-ROM + CHR-ROM
+pub fn lookup(crc32: u32, sha1: &str) -> Option<&'static GameInfo> {
+    GAME_DB.get(&crc32).filter(|info| match &info.sha1 {
+        Some(expected) => expected.eq_ignore_ascii_case(sha1),
+        None => true,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_embedded_db() {
+        let generated = parse(GENERATED_DB).unwrap();
+        let local = parse(LOCAL_DB).unwrap();
+        assert!(GAME_DB.len() >= generated.len().max(local.len()));
+        assert_eq!(GAME_DB[&0x862A5C36].mirroring, Some(Mirroring::VERTICAL));
+
+        assert_eq!(
+            parse("# comment\n862A5C36;;0;0;X;0;NTSC;Snake"),
+            Err(GameDbError::Malformed { line: 2, text: "862A5C36;;0;0;X;0;NTSC;Snake".to_string() })
+        );
+        assert_eq!(parse("00000001;;1;0;M;1;PAL;Banked").unwrap()[&1].mirroring, None);
+    }
+}
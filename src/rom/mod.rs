@@ -1,34 +1,211 @@
-#[derive(Debug, PartialEq)]
+pub mod archive;
+pub mod gamedb;
+pub mod patch;
+
+use gamedb::GameInfo;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HeaderFormat {
+    INes,
+    Nes20,
+}
+
+// CPU/PPU timing, header byte 12 in NES 2.0 (byte 9 bit 0 in iNES)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+impl Timing {
+    // Dot count per frame divided by the PPU/CPU clock ratio, rounded
+    pub fn cpu_cycles_per_frame(&self) -> usize {
+        match self {
+            Timing::Ntsc | Timing::MultiRegion => 29781,
+            Timing::Pal => 33248,
+            Timing::Dendy => 35464,
+        }
+    }
+
+    // CPU clock in Hz
+    pub fn cpu_clock_rate(&self) -> usize {
+        match self {
+            Timing::Ntsc | Timing::MultiRegion => 1_789_773,
+            Timing::Pal => 1_662_607,
+            Timing::Dendy => 1_773_448,
+        }
+    }
+
+    // Including vblank and the pre-render line
+    pub fn scanlines(&self) -> usize {
+        match self {
+            Timing::Ntsc | Timing::MultiRegion => 262,
+            Timing::Pal | Timing::Dendy => 312,
+        }
+    }
+
+    // PPU dots in `cycles` CPU cycles. PAL runs 3.2 dots per cycle, the rest 3
+    pub fn ppu_dots(&self, cycles: usize) -> usize {
+        match self {
+            Timing::Pal => cycles * 16 / 5,
+            _ => cycles * 3,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConsoleType {
+    Nes,
+    // PPU type and hardware type are taken from header byte 13
+    VsSystem { ppu: u8, hardware: u8 },
+    Playchoice10,
+    // Extended console type from the low nibble of header byte 13
+    Extended(u8),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+    // File is shorter than the section it's supposed to contain
+    Truncated { section: &'static str, expected: usize, actual: usize },
+    InvalidTag,
+    NoPrgRom,
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomError::Truncated { section, expected, actual } => write!(
+                f, "File is truncated: {} needs {} bytes, but only {} are left", section, expected, actual
+            ),
+            RomError::InvalidTag => write!(f, "File is not in iNES file format"),
+            RomError::NoPrgRom => write!(f, "Header declares no PRG-ROM"),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+// Non-fatal problems found while parsing, the ROM is still usable
+#[derive(Debug, PartialEq)]
+pub enum RomWarning {
+    // Bytes 7-15 contain garbage from an old dumping tool (e.g. "DiskDude!"), they were ignored
+    DirtyHeader,
+    // File has more bytes after PRG and CHR than the header declares
+    TrailingData(usize),
+    // Header disagrees with the game database, database values were used
+    HeaderCorrected,
+}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    // 512 bytes mapped at $7000-$71FF
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
     pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    pub format: HeaderFormat,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub expansion_device: u8,
+    pub warnings: Vec<RomWarning>,
+    // Checksums of PRG-ROM + CHR-ROM, SHA-1 as uppercase hex
+    pub crc32: u32,
+    pub sha1: String,
+    // Game database entry matching the checksums
+    pub game: Option<GameInfo>,
 }
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+// Far above the largest NES ROMs, but small enough to allocate. Archives and
+// patches aren't unpacked past it
+pub const MAX_ROM_SIZE: usize = 64 * 1024 * 1024;
+
+// NES 2.0 ROM size: either a 12-bit page count, or when the MSB nibble is $F
+// the LSB byte holds EEEEEEMM and the size is 2^E * (MM * 2 + 1) bytes
+fn nes20_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+
+        // Saturate so that absurd sizes end up as a truncation error instead of an overflow
+        2usize.checked_pow(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .unwrap_or(usize::MAX)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * page_size
+    }
+}
+
+// NES 2.0 RAM size: shift count of 0 means no RAM, otherwise 64 << shift bytes
+fn nes20_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+// Bounds checked slice of `len` bytes at `start`
+fn section<'a>(raw: &'a [u8], start: usize, len: usize, name: &'static str) -> Result<&'a [u8], RomError> {
+    let available = raw.len().saturating_sub(start);
+
+    if available < len {
+        return Err(RomError::Truncated { section: name, expected: len, actual: available });
+    }
+
+    Ok(&raw[start..start + len])
+}
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+    pub fn new(file: &[u8]) -> Result<Rom, RomError> {
+        let header = section(file, 0, HEADER_SIZE, "header")?;
+
+        if header[0..4] != NES_TAG {
+            return Err(RomError::InvalidTag);
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let mut warnings = vec![];
 
-        let ines_ver = (raw[7] >> 2) & 0b11;
+        // Copy the header so that garbage in bytes 7-15 can be cleared
+        let mut raw_header = [0u8; HEADER_SIZE];
+        raw_header.copy_from_slice(header);
+        let raw = &mut raw_header;
 
-        if ines_ver != 0 {
-            return Err("iNES 2.0 format isn't supported".to_string());
-        }
+        let format = match (raw[7] >> 2) & 0b11 {
+            2 => HeaderFormat::Nes20,
+            ver => {
+                // Archaic iNES: version bits other than 0 or non-zero padding in bytes 12-15
+                // mean a tool wrote a signature over the header, only bytes 4-6 can be trusted
+                if ver != 0 || raw[12..16].iter().any(|&b| b != 0) {
+                    raw[7..16].fill(0);
+                    warnings.push(RomWarning::DirtyHeader);
+                }
+
+                HeaderFormat::INes
+            }
+        };
 
         let four_screen = raw[6] & 0b1000 != 0;
         let verical_mirroring = raw[6] & 0b1 != 0;
@@ -38,21 +215,310 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let battery = raw[6] & 0b10 != 0;
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+
+        let prg_rom_size;
+        let chr_rom_size;
+        let submapper;
+        let prg_ram_size;
+        let prg_nvram_size;
+        let chr_ram_size;
+        let chr_nvram_size;
+        let timing;
+        let console_type;
+        let misc_roms;
+        let expansion_device;
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+        match format {
+            HeaderFormat::INes => {
+                prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+                chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+                submapper = 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+                // Byte 8 is PRG-RAM size in 8K units, 0 infers 8K for compatibility
+                let ram_size = raw[8].max(1) as usize * PRG_RAM_PAGE_SIZE;
+                (prg_ram_size, prg_nvram_size) = if battery { (0, ram_size) } else { (ram_size, 0) };
 
-        Ok(
-            Rom {
-                prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-                chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-                mapper: mapper,
-                screen_mirroring: screen_mirroring,
+                chr_ram_size = if chr_rom_size == 0 { CHR_ROM_PAGE_SIZE } else { 0 };
+                chr_nvram_size = 0;
+
+                timing = if raw[9] & 0b1 != 0 { Timing::Pal } else { Timing::Ntsc };
+                console_type = match raw[7] & 0b11 {
+                    0b01 => ConsoleType::VsSystem { ppu: 0, hardware: 0 },
+                    0b10 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Nes,
+                };
+                misc_roms = 0;
+                expansion_device = 0;
             }
-        )
+
+            HeaderFormat::Nes20 => {
+                mapper |= ((raw[8] & 0b1111) as u16) << 8;
+                submapper = raw[8] >> 4;
+
+                prg_rom_size = nes20_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE);
+                chr_rom_size = nes20_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE);
+
+                prg_ram_size = nes20_ram_size(raw[10] & 0b1111);
+                prg_nvram_size = nes20_ram_size(raw[10] >> 4);
+                chr_ram_size = nes20_ram_size(raw[11] & 0b1111);
+                chr_nvram_size = nes20_ram_size(raw[11] >> 4);
+
+                timing = match raw[12] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                console_type = match raw[7] & 0b11 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem { ppu: raw[13] & 0b1111, hardware: raw[13] >> 4 },
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Extended(raw[13] & 0b1111),
+                };
+                misc_roms = raw[14] & 0b11;
+                expansion_device = raw[15] & 0b11_1111;
+            }
+        }
+
+        if prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
+
+        let has_trainer = raw[6] & 0b100 != 0;
+
+        let trainer = if has_trainer {
+            Some(section(file, HEADER_SIZE, TRAINER_SIZE, "trainer")?.to_vec())
+        } else {
+            None
+        };
+
+        let prg_rom_start = HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start.saturating_add(prg_rom_size);
+        let rom_end = chr_rom_start.saturating_add(chr_rom_size);
+
+        let prg_rom = section(file, prg_rom_start, prg_rom_size, "PRG-ROM")?.to_vec();
+        let chr_rom = section(file, chr_rom_start, chr_rom_size, "CHR-ROM")?.to_vec();
+
+        if file.len() > rom_end {
+            warnings.push(RomWarning::TrailingData(file.len() - rom_end));
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&prg_rom);
+        hasher.update(&chr_rom);
+        let crc32 = hasher.finalize();
+
+        let mut sha1 = sha1_smol::Sha1::new();
+        sha1.update(&prg_rom);
+        sha1.update(&chr_rom);
+        let sha1 = sha1.digest().to_string().to_uppercase();
+
+        let game = gamedb::lookup(crc32, &sha1).cloned();
+
+        let mut rom = Rom {
+            prg_rom,
+            chr_rom,
+            trainer,
+            mapper,
+            submapper,
+            screen_mirroring,
+            battery,
+            format,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            timing,
+            console_type,
+            misc_roms,
+            expansion_device,
+            warnings,
+            crc32,
+            sha1,
+            game,
+        };
+
+        rom.apply_game_info();
+
+        Ok(rom)
+    }
+
+    // Trust the game database over iNES 1.0 headers, including the ones
+    // cleaned of DiskDude-style garbage, they're often wrong. NES 2.0 headers
+    // are kept as they are
+    fn apply_game_info(&mut self) {
+        let Some(game) = &self.game else {
+            return;
+        };
+        if self.format != HeaderFormat::INes {
+            return;
+        }
+        let mirroring = game.mirroring.unwrap_or(self.screen_mirroring);
+
+        let corrected = self.mapper != game.mapper
+            || self.submapper != game.submapper
+            || self.screen_mirroring != mirroring
+            || self.battery != game.battery
+            || self.timing != game.region;
+
+        self.mapper = game.mapper;
+        self.submapper = game.submapper;
+        self.screen_mirroring = mirroring;
+        self.timing = game.region;
+
+        if self.battery != game.battery {
+            self.battery = game.battery;
+
+            let ram_size = self.prg_ram_size + self.prg_nvram_size;
+            (self.prg_ram_size, self.prg_nvram_size) = if self.battery { (0, ram_size) } else { (ram_size, 0) };
+        }
+
+        if corrected {
+            self.warnings.push(RomWarning::HeaderCorrected);
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.game.as_ref().map(|game| game.title.as_str())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn image(header: [u8; 16], prg_size: usize, chr_size: usize) -> Vec<u8> {
+        let mut raw = header.to_vec();
+        raw.resize(16 + prg_size + chr_size, 0);
+        raw
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let raw = image(
+            [0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0x00, 0, 0, 0, 0, 0, 0, 0, 0],
+            2 * PRG_ROM_PAGE_SIZE, CHR_ROM_PAGE_SIZE,
+        );
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.format, HeaderFormat::INes);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_ram_size, PRG_RAM_PAGE_SIZE);
+        assert_eq!(rom.timing, Timing::Ntsc);
+    }
+
+    #[test]
+    fn test_nes20_header() {
+        let raw = image(
+            [0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x12, 0x58, 0x31, 0x00, 0x70, 0x07, 0x03, 0x00, 0x00, 0x01],
+            2 * PRG_ROM_PAGE_SIZE, 0,
+        );
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.format, HeaderFormat::Nes20);
+        assert_eq!(rom.mapper, 0x151);
+        assert_eq!(rom.submapper, 3);
+        assert!(rom.battery);
+        assert_eq!(rom.prg_ram_size, 0);
+        assert_eq!(rom.prg_nvram_size, 8192);
+        assert_eq!(rom.chr_ram_size, 8192);
+        assert_eq!(rom.chr_nvram_size, 0);
+        assert_eq!(rom.timing, Timing::Dendy);
+        assert_eq!(rom.console_type, ConsoleType::Nes);
+        assert_eq!(rom.expansion_device, 1);
+    }
+
+    #[test]
+    fn test_nes20_exponent_multiplier_size() {
+        // E = 14, MM = 1: 2^14 * 3 = 48K of PRG-ROM
+        let raw = image(
+            [0x4E, 0x45, 0x53, 0x1A, 0b0011_1001, 0x00, 0x00, 0x09, 0x00, 0x0F, 0, 0, 0, 0x21, 0, 0],
+            3 * PRG_ROM_PAGE_SIZE, 0,
+        );
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.prg_rom.len(), 3 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.console_type, ConsoleType::VsSystem { ppu: 1, hardware: 2 });
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        assert_eq!(
+            Rom::new(&[0x4E, 0x45, 0x53]).err(),
+            Some(RomError::Truncated { section: "header", expected: 16, actual: 3 })
+        );
+
+        let mut raw = image(
+            [0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            2 * PRG_ROM_PAGE_SIZE, 0,
+        );
+        raw.truncate(raw.len() - 100);
+
+        assert_eq!(
+            Rom::new(&raw).err(),
+            Some(RomError::Truncated { section: "PRG-ROM", expected: 2 * PRG_ROM_PAGE_SIZE, actual: 2 * PRG_ROM_PAGE_SIZE - 100 })
+        );
+    }
+
+    #[test]
+    fn test_diskdude_header() {
+        let mut header = [0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        header[7..16].copy_from_slice(b"DiskDude!");
+        let rom = Rom::new(&image(header, PRG_ROM_PAGE_SIZE, CHR_ROM_PAGE_SIZE)).unwrap();
+
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.format, HeaderFormat::INes);
+        assert_eq!(rom.console_type, ConsoleType::Nes);
+        assert_eq!(rom.warnings, vec![RomWarning::DirtyHeader]);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut raw = image(
+            [0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0b100, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            TRAINER_SIZE + PRG_ROM_PAGE_SIZE, 0,
+        );
+        raw[16] = 0xAB;
+        raw[16 + TRAINER_SIZE] = 0xCD;
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.trainer.as_ref().map(|t| (t.len(), t[0])), Some((TRAINER_SIZE, 0xAB)));
+        assert_eq!(rom.prg_rom[0], 0xCD);
+    }
+
+    #[test]
+    fn test_game_db_corrects_header() {
+        // nestest with a bogus mapper and vertical mirroring in the header
+        let mut raw = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+        raw[6] = 0x41;
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.crc32, 0x158B0388);
+        assert_eq!(rom.title(), Some("nestest"));
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+        assert_eq!(rom.warnings, vec![RomWarning::HeaderCorrected]);
+
+        // A wrong region alone counts as a correction too
+        let mut raw = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+        raw[9] = 0x01;
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.timing, Timing::Ntsc);
+        assert_eq!(rom.warnings, vec![RomWarning::HeaderCorrected]);
+
+        // A NES 2.0 header is taken as it is
+        let mut raw = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+        raw[6] = 0x01;
+        raw[7] = 0x08;
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.title(), Some("nestest"));
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert!(rom.warnings.is_empty());
+    }
+}
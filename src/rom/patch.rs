@@ -0,0 +1,448 @@
+use crate::rom::MAX_ROM_SIZE;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    // Patch was made for a different ROM
+    SourceChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+    // Patch file itself is corrupted
+    PatchChecksum { expected: u32, actual: u32 },
+    SourceSize { expected: usize, actual: usize },
+    // Patched ROM would be bigger than any real one, likely a corrupt patch
+    TargetSize { max: usize, actual: usize },
+    OutOfBounds,
+    Io(String),
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Patch isn't in IPS, BPS or UPS format"),
+            PatchError::Truncated => write!(f, "Patch file is truncated"),
+            PatchError::SourceChecksum { expected, actual } => write!(
+                f, "Patch expects a ROM with CRC32 {:08X}, but it's {:08X}", expected, actual
+            ),
+            PatchError::TargetChecksum { expected, actual } => write!(
+                f, "Patched ROM should have CRC32 {:08X}, but it's {:08X}", expected, actual
+            ),
+            PatchError::PatchChecksum { expected, actual } => write!(
+                f, "Patch is corrupted: CRC32 should be {:08X}, but it's {:08X}", expected, actual
+            ),
+            PatchError::SourceSize { expected, actual } => write!(
+                f, "Patch expects a ROM of {} bytes, but it's {} bytes", expected, actual
+            ),
+            PatchError::TargetSize { max, actual } => write!(
+                f, "Patch makes a ROM of {} bytes, more than the {} allowed", actual, max
+            ),
+            PatchError::OutOfBounds => write!(f, "Patch refers to data outside of the ROM"),
+            PatchError::Io(err) => write!(f, "Failed to read patch: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+const IPS_TAG: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_TAG: &[u8] = b"BPS1";
+const UPS_TAG: &[u8] = b"UPS1";
+// Source, target and patch CRC32 at the end of BPS and UPS files
+const FOOTER_SIZE: usize = 12;
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+// Looks for a patch with the same name as the ROM, e.g. game.nes -> game.ips
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+pub fn apply_file(path: &Path, rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let patch = fs::read(path).map_err(|err| PatchError::Io(err.to_string()))?;
+    apply(&patch, rom)
+}
+
+// Detects the patch format by its tag and applies it to the whole ROM file
+pub fn apply(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_TAG) {
+        apply_ips(patch, rom)
+    } else if patch.starts_with(BPS_TAG) {
+        apply_bps(patch, rom)
+    } else if patch.starts_with(UPS_TAG) {
+        apply_ups(patch, rom)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self) -> Result<u8, PatchError> {
+        let value = *self.data.get(self.pos).ok_or(PatchError::Truncated)?;
+        self.pos += 1;
+
+        Ok(value)
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.pos.checked_add(len).ok_or(PatchError::Truncated)?;
+        let slice = self.data.get(self.pos..end).ok_or(PatchError::Truncated)?;
+        self.pos = end;
+
+        Ok(slice)
+    }
+
+    fn read_be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.read_slice(len)?.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    // Variable length number used by BPS and UPS
+    fn read_varint(&mut self) -> Result<usize, PatchError> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+
+        loop {
+            let x = self.read()?;
+            data = data.checked_add((x & 0x7F) as usize * shift).ok_or(PatchError::OutOfBounds)?;
+
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+            data = data.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Checks the patch CRC32 and returns the expected source and target CRC32
+fn check_footer(patch: &[u8]) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let expected = read_u32_le(&footer[8..]);
+    let actual = crc32fast::hash(&patch[..patch.len() - 4]);
+
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+
+    Ok((read_u32_le(&footer[0..]), read_u32_le(&footer[4..])))
+}
+
+fn check_source(rom: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32fast::hash(rom);
+
+    if expected != actual {
+        return Err(PatchError::SourceChecksum { expected, actual });
+    }
+
+    Ok(())
+}
+
+fn check_target_size(target_size: usize) -> Result<(), PatchError> {
+    if target_size > MAX_ROM_SIZE {
+        return Err(PatchError::TargetSize { max: MAX_ROM_SIZE, actual: target_size });
+    }
+
+    Ok(())
+}
+
+// `len` more bytes have to fit into the `target_size` the patch announced
+fn check_room(target: &[u8], len: usize, target_size: usize) -> Result<(), PatchError> {
+    match target.len().checked_add(len) {
+        Some(end) if end <= target_size => Ok(()),
+        _ => Err(PatchError::OutOfBounds),
+    }
+}
+
+// rom[start..start + len] without overflowing
+fn source_slice(rom: &[u8], start: usize, len: usize) -> Result<&[u8], PatchError> {
+    let end = start.checked_add(len).ok_or(PatchError::OutOfBounds)?;
+    rom.get(start..end).ok_or(PatchError::OutOfBounds)
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32fast::hash(target);
+
+    if expected != actual {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+
+    Ok(())
+}
+
+// http://fileformats.archiveteam.org/wiki/IPS_(binary_patch_format)
+fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = Reader { data: patch, pos: IPS_TAG.len() };
+    let mut target = rom.to_vec();
+
+    loop {
+        let record = reader.read_slice(3)?;
+        if record == IPS_EOF {
+            break;
+        }
+
+        let offset = record.iter().fold(0, |acc, &b| acc << 8 | b as usize);
+        let size = reader.read_be(2)?;
+
+        // Size 0 is an RLE record: 2 bytes of count and the value to repeat
+        let (count, data) = if size == 0 {
+            let count = reader.read_be(2)?;
+            (count, None)
+        } else {
+            (size, Some(reader.read_slice(size)?))
+        };
+
+        if target.len() < offset + count {
+            target.resize(offset + count, 0);
+        }
+
+        match data {
+            Some(data) => target[offset..offset + count].copy_from_slice(data),
+            None => {
+                let value = reader.read()?;
+                target[offset..offset + count].fill(value);
+            }
+        }
+    }
+
+    // Optional extension: 3 bytes after EOF truncate the file
+    if let Ok(size) = reader.read_be(3) {
+        target.truncate(size);
+    }
+
+    Ok(target)
+}
+
+// https://github.com/blakesmith/rombp/blob/master/docs/bps_spec.md
+fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    check_source(rom, source_crc)?;
+
+    let actions = &patch[..patch.len() - FOOTER_SIZE];
+    let mut reader = Reader { data: actions, pos: BPS_TAG.len() };
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    let metadata_size = reader.read_varint()?;
+    reader.read_slice(metadata_size)?;
+
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize { expected: source_size, actual: rom.len() });
+    }
+    check_target_size(target_size)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    while reader.pos < actions.len() {
+        let data = reader.read_varint()?;
+        let len = (data >> 2) + 1;
+        check_room(&target, len, target_size)?;
+
+        match data & 0b11 {
+            // SourceRead
+            0 => {
+                let bytes = source_slice(rom, target.len(), len)?;
+                target.extend_from_slice(bytes);
+            }
+
+            // TargetRead
+            1 => target.extend_from_slice(reader.read_slice(len)?),
+
+            // SourceCopy
+            2 => {
+                source_offset = relative_offset(source_offset, reader.read_varint()?)?;
+                let bytes = source_slice(rom, source_offset, len)?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            }
+
+            // TargetCopy, may overlap with the bytes it produces
+            _ => {
+                target_offset = relative_offset(target_offset, reader.read_varint()?)?;
+                if target_offset >= target.len() {
+                    return Err(PatchError::OutOfBounds);
+                }
+
+                for _ in 0..len {
+                    target.push(target[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    check_target(&target, target_crc)?;
+
+    Ok(target)
+}
+
+// BPS offsets are stored as sign bit in bit 0 and magnitude in the rest
+fn relative_offset(base: usize, data: usize) -> Result<usize, PatchError> {
+    let delta = data >> 1;
+
+    if data & 1 != 0 {
+        base.checked_sub(delta).ok_or(PatchError::OutOfBounds)
+    } else {
+        base.checked_add(delta).ok_or(PatchError::OutOfBounds)
+    }
+}
+
+// http://fileformats.archiveteam.org/wiki/UPS_(binary_patch_format)
+fn apply_ups(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    check_source(rom, source_crc)?;
+
+    let hunks = &patch[..patch.len() - FOOTER_SIZE];
+    let mut reader = Reader { data: hunks, pos: UPS_TAG.len() };
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize { expected: source_size, actual: rom.len() });
+    }
+    check_target_size(target_size)?;
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+
+    let mut offset: usize = 0;
+
+    while reader.pos < hunks.len() {
+        offset = offset.checked_add(reader.read_varint()?).ok_or(PatchError::OutOfBounds)?;
+
+        // XOR bytes until a zero byte, which itself skips one byte
+        loop {
+            let x = reader.read()?;
+            if x == 0 {
+                offset = offset.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+                break;
+            }
+
+            *target.get_mut(offset).ok_or(PatchError::OutOfBounds)? ^= x;
+            // Inside the target, so this can't overflow
+            offset += 1;
+        }
+    }
+
+    check_target(&target, target_crc)?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at offset 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, b'x', b'y']);
+        // RLE: 3 times 'z' at offset 5
+        patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, b'z']);
+        patch.extend_from_slice(b"EOF");
+
+        assert_eq!(apply(&patch, b"ABCDE").unwrap(), b"AxyDEzzz");
+    }
+
+    #[test]
+    fn test_bps() {
+        let mut patch = b"BPS1".to_vec();
+        // Sizes and empty metadata
+        patch.extend_from_slice(&[0x84, 0x85, 0x80]);
+        // SourceRead 2, TargetRead "X", SourceRead 1, TargetRead "E"
+        patch.extend_from_slice(&[0x84, 0x81, b'X', 0x80, 0x81, b'E']);
+        let patch = with_footer(patch, b"ABCD", b"ABXDE");
+
+        assert_eq!(apply(&patch, b"ABCD").unwrap(), b"ABXDE");
+        assert_eq!(
+            apply(&patch, b"ABCE").err(),
+            Some(PatchError::SourceChecksum { expected: crc32fast::hash(b"ABCD"), actual: crc32fast::hash(b"ABCE") })
+        );
+    }
+
+    #[test]
+    fn test_ups() {
+        let mut patch = b"UPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x85]);
+        // Skip 2 bytes, XOR 'C' into 'X', then append 'E'
+        patch.extend_from_slice(&[0x82, b'C' ^ b'X', 0x00, 0x80, b'E', 0x00]);
+        let patch = with_footer(patch, b"ABCD", b"ABXDE");
+
+        assert_eq!(apply(&patch, b"ABCD").unwrap(), b"ABXDE");
+    }
+
+    #[test]
+    fn test_corrupted_patch() {
+        let mut patch = with_footer(b"UPS1\x84\x84".to_vec(), b"ABCD", b"ABCD");
+        patch[4] = 0x85;
+
+        assert!(matches!(apply(&patch, b"ABCD"), Err(PatchError::PatchChecksum { .. })));
+    }
+
+    fn varint(mut value: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(x | 0x80);
+                return bytes;
+            }
+            bytes.push(x);
+            value -= 1;
+        }
+    }
+
+    #[test]
+    fn test_oversized_patch() {
+        // Target sizes are checked before anything is allocated
+        let huge = 1 << 40;
+        for tag in [&b"BPS1"[..], b"UPS1"] {
+            let patch = with_footer([tag, &[0x84], &varint(huge), &[0x80]].concat(), b"ABCD", b"");
+            assert_eq!(apply(&patch, b"ABCD").err(), Some(PatchError::TargetSize { max: MAX_ROM_SIZE, actual: huge }));
+        }
+
+        // TargetRead "A", then a TargetCopy of 2^40 bytes into a 5 byte target
+        let mut patch = b"BPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x85, 0x80, 0x81, b'A']);
+        patch.extend(varint((huge - 1) << 2 | 3));
+        patch.push(0x80);
+        assert_eq!(apply(&with_footer(patch, b"ABCD", b""), b"ABCD").err(), Some(PatchError::OutOfBounds));
+
+        // SourceCopy from an offset where offset + len overflows
+        let mut patch = b"BPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x85, 0x80]);
+        patch.extend(varint(2));
+        patch.extend(varint((usize::MAX >> 2) << 1));
+        assert_eq!(apply(&with_footer(patch, b"ABCD", b""), b"ABCD").err(), Some(PatchError::OutOfBounds));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::nes::Nes;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Layout: "FMST", u16 version, 40 byte SHA-1 of the ROM in hex, then one
+// section per component: 4 byte tag, u32 length and the component's fields.
+// All numbers are little endian. Bump the version whenever a section changes
+const STATE_TAG: [u8; 4] = *b"FMST";
+pub const STATE_VERSION: u16 = 2;
+const SHA1_LEN: usize = 40;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    InvalidTag,
+    Version { expected: u16, actual: u16 },
+    // State was saved with another game, hashes are SHA-1 in hex
+    RomMismatch { expected: String, actual: String },
+    Truncated,
+    // Section is missing, out of order or doesn't have the expected size
+    BadSection(&'static str),
+    Io(String),
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::InvalidTag => write!(f, "Not a FamEmu save state"),
+            StateError::Version { expected, actual } => write!(
+                f, "Save state version {} isn't supported, expected {}", actual, expected
+            ),
+            StateError::RomMismatch { expected, actual } => write!(
+                f, "Save state belongs to another ROM (SHA-1 {}, loaded {})", actual, expected
+            ),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::BadSection(section) => write!(f, "Save state has a broken {} section", section),
+            StateError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        StateWriter { data: vec![] }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed, so the reader can check it against the current cart
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], write: F) {
+        let mut section = StateWriter::new();
+        write(&mut section);
+
+        self.data.extend_from_slice(tag);
+        self.u32(section.data.len() as u32);
+        self.data.extend_from_slice(&section.data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    section: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    fn new(data: &'a [u8], section: &'static str) -> Self {
+        StateReader { data, pos: 0, section }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(StateError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // Reads a length prefixed block into `out`, which must have the same size
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let len = self.u32()? as usize;
+        if len != out.len() {
+            return Err(StateError::BadSection(self.section));
+        }
+
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    pub fn section<F>(&mut self, tag: &[u8; 4], name: &'static str, read: F) -> Result<(), StateError>
+    where
+        F: FnOnce(&mut StateReader<'a>) -> Result<(), StateError>
+    {
+        if self.take(4)? != tag {
+            return Err(StateError::BadSection(name));
+        }
+
+        let len = self.u32()? as usize;
+        let mut section = StateReader::new(self.take(len)?, name);
+        read(&mut section)?;
+
+        if section.pos != len {
+            return Err(StateError::BadSection(name));
+        }
+        Ok(())
+    }
+}
+
+pub fn save(nes: &Nes) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    writer.data.extend_from_slice(&STATE_TAG);
+    writer.u16(STATE_VERSION);
+    writer.data.extend_from_slice(format!("{:<1$}", nes.rom().sha1, SHA1_LEN).as_bytes());
+
+    nes.save_state(&mut writer);
+
+    writer.data
+}
+
+// Checks the header before touching the machine. If a section turns out to be
+// broken, the state from before the call is put back
+pub fn load(nes: &mut Nes, data: &[u8]) -> Result<(), StateError> {
+    let mut reader = StateReader::new(data, "header");
+
+    if reader.take(4).map_err(|_| StateError::InvalidTag)? != STATE_TAG {
+        return Err(StateError::InvalidTag);
+    }
+
+    let version = reader.u16()?;
+    if version != STATE_VERSION {
+        return Err(StateError::Version { expected: STATE_VERSION, actual: version });
+    }
+
+    let sha1 = String::from_utf8_lossy(reader.take(SHA1_LEN)?).trim_end().to_string();
+    if sha1 != nes.rom().sha1 {
+        return Err(StateError::RomMismatch { expected: nes.rom().sha1.clone(), actual: sha1 });
+    }
+
+    let backup = save(nes);
+    let mut result = nes.load_state(&mut reader);
+    if result.is_ok() && reader.pos != data.len() {
+        result = Err(StateError::BadSection("trailing"));
+    }
+
+    if result.is_err() {
+        let mut reader = StateReader::new(&backup[4 + 2 + SHA1_LEN..], "backup");
+        nes.load_state(&mut reader).expect("Failed to restore the state saved a moment ago");
+    }
+
+    result
+}
+
+// Quick save slots live next to the ROM: game.ss0 .. game.ss9
+pub fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+pub fn save_slot(nes: &Nes, rom_path: &Path, slot: u8) -> Result<PathBuf, StateError> {
+    let path = slot_path(rom_path, slot);
+    fs::write(&path, save(nes)).map_err(|err| StateError::Io(err.to_string()))?;
+
+    Ok(path)
+}
+
+pub fn load_slot(nes: &mut Nes, rom_path: &Path, slot: u8) -> Result<PathBuf, StateError> {
+    let path = slot_path(rom_path, slot);
+    let data = fs::read(&path).map_err(|err| StateError::Io(format!("{}: {}", path.display(), err)))?;
+    load(nes, &data)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::mem::Mem;
+    use crate::nes::test_util;
+
+    #[test]
+    fn test_round_trip() {
+        let mut nes = test_util::load("nestest.nes");
+        nes.run_frame();
+        let state = save(&nes);
+        let (pc, cycles, ram) = (nes.cpu().program_counter, nes.bus().cycles(), crate::headless::dump_ram(nes.bus()));
+
+        nes.run_frame();
+        nes.cpu_mut().mem_write(0x0010, 0x99);
+        load(&mut nes, &state).unwrap();
+
+        assert_eq!(nes.cpu().program_counter, pc);
+        assert_eq!(nes.bus().cycles(), cycles);
+        assert_eq!(nes.frame_count(), 1);
+        assert_eq!(crate::headless::dump_ram(nes.bus()), ram);
+    }
+
+    #[test]
+    fn test_rejects_other_rom_and_version() {
+        let snake = test_util::load("snake.nes");
+        let mut nes = test_util::load("nestest.nes");
+        assert!(matches!(load(&mut nes, &save(&snake)), Err(StateError::RomMismatch { .. })));
+
+        let mut state = save(&nes);
+        state[4] = 0xFF;
+        assert!(matches!(load(&mut nes, &state), Err(StateError::Version { .. })));
+    }
+
+    #[test]
+    fn test_truncated_state_is_rolled_back() {
+        let mut nes = test_util::load("nestest.nes");
+        let state = save(&nes);
+        nes.run_frame();
+        let pc = nes.cpu().program_counter;
+
+        assert_eq!(load(&mut nes, &state[..state.len() - 10]), Err(StateError::Truncated));
+        assert_eq!(nes.cpu().program_counter, pc);
+        assert_eq!(nes.frame_count(), 1);
+    }
+}
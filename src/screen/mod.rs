@@ -0,0 +1,48 @@
+use crate::bus::Bus;
+
+// The snake demo draws a 32x32 screen from $0200-$05FF, one byte per pixel
+pub const WIDTH: usize = 32;
+pub const HEIGHT: usize = 32;
+pub const FRAME_SIZE: usize = WIDTH * HEIGHT * 3;
+
+// RGB24 image of the screen
+pub type Frame = [u8; FRAME_SIZE];
+
+const SCREEN: u16 = 0x0200;
+const SCREEN_END: u16 = 0x0600;
+
+pub fn color(byte: u8) -> (u8, u8, u8) {
+    match byte {
+        0 => (0, 0, 0),
+        1 => (255, 255, 255),
+        2 | 9 => (128, 128, 128),
+        3 | 10 => (255, 0, 0),
+        4 | 11 => (0, 255, 0),
+        5 | 12 => (0, 0, 255),
+        6 | 13 => (255, 0, 255),
+        7 | 14 => (255, 255, 0),
+        _ => (0, 255, 255)
+    }
+}
+
+// Copies the screen into an RGB24 frame, returns true if anything changed
+pub fn read_screen_state(bus: &Bus, frame: &mut Frame) -> bool {
+    let mut frame_idx = 0;
+    let mut update = false;
+
+    for i in SCREEN..SCREEN_END {
+        let color_idx = bus.peek(i);
+        let (b1, b2, b3) = color(color_idx);
+
+        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
+            frame[frame_idx] = b1;
+            frame[frame_idx + 1] = b2;
+            frame[frame_idx + 2] = b3;
+
+            update = true;
+        }
+        frame_idx += 3;
+    }
+
+    update
+}
@@ -0,0 +1,340 @@
+use crate::bus::{CARTRIDGE_ROM, PRG_RAM};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// FCEUX keeps one .nl file per 16K bank, and Disasm works in the same units
+pub const PRG_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    // A CPU address: RAM, registers, or ROM when the file doesn't say which bank
+    Cpu(u16),
+    // Offset into PRG-ROM, so the label only shows while its bank is mapped
+    Prg(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub location: Location,
+    // Bytes the label covers, e.g. the length of an array
+    pub size: u16,
+}
+
+impl Symbol {
+    pub fn bank(&self) -> Option<usize> {
+        match self.location {
+            Location::Prg(offset) => Some(offset / PRG_BANK_SIZE),
+            Location::Cpu(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SymbolError {
+    UnknownFormat,
+    Syntax { line: usize, message: String },
+    Io(String),
+}
+
+impl std::fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymbolError::UnknownFormat => write!(f, "Symbol file isn't a ca65 .dbg, FCEUX .nl or Mesen .mlb file"),
+            SymbolError::Syntax { line, message } => write!(f, "Line {}: {}", line, message),
+            SymbolError::Io(err) => write!(f, "Failed to read symbols: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+// Labels from symbol files. Where two files name the same location the
+// first one loaded wins
+#[derive(Debug, Default)]
+pub struct Symbols {
+    list: Vec<Symbol>,
+    by_location: HashMap<Location, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Symbols {
+    pub fn add(&mut self, symbol: Symbol) {
+        let index = self.list.len();
+        self.by_location.entry(symbol.location).or_insert(index);
+        self.by_name.entry(symbol.name.clone()).or_insert(index);
+        self.list.push(symbol);
+    }
+
+    pub fn list(&self) -> &[Symbol] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn at(&self, location: Location) -> Option<&Symbol> {
+        self.by_location.get(&location).map(|index| &self.list[*index])
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|index| &self.list[*index])
+    }
+
+    // Picks the format from the file name, returns how many symbols were added
+    pub fn load_file(&mut self, path: &Path) -> Result<usize, SymbolError> {
+        let text = fs::read_to_string(path).map_err(|err| SymbolError::Io(err.to_string()))?;
+        let extension = |path: &Path| path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+
+        match extension(path).as_deref() {
+            Some("dbg") => self.load_dbg(&text),
+            Some("mlb") => self.load_mlb(&text),
+            // game.nes.ram.nl holds RAM labels, game.nes.3.nl those of bank 3
+            Some("nl") => {
+                let bank = extension(&path.with_extension("")).and_then(|bank| usize::from_str_radix(&bank, 16).ok());
+                self.load_nl(&text, bank)
+            }
+            _ => Err(SymbolError::UnknownFormat),
+        }
+    }
+
+    // FCEUX: `$C000#name#comment`, or `$0300/10#name#` for a 16 byte array.
+    // Addresses in a bank file are CPU addresses as the bank is mapped
+    pub fn load_nl(&mut self, text: &str, bank: Option<usize>) -> Result<usize, SymbolError> {
+        let mut count = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            // Comments continue on lines starting with `\`
+            let Some(line) = line.strip_prefix('$') else { continue };
+            let syntax = |message: &str| SymbolError::Syntax { line: i + 1, message: message.to_string() };
+
+            let mut fields = line.split('#');
+            let addr = fields.next().unwrap();
+            let (addr, size) = match addr.split_once('/') {
+                Some((addr, size)) => (addr, parse_hex(size).ok_or_else(|| syntax("Bad array size"))?),
+                None => (addr, 1),
+            };
+            let size = u16::try_from(size.max(1)).map_err(|_| syntax("Array size is out of range"))?;
+            let addr = u16::try_from(parse_hex(addr).ok_or_else(|| syntax("Bad address"))?)
+                .map_err(|_| syntax("Address is out of range"))?;
+            let name = fields.next().ok_or_else(|| syntax("Expected $ADDR#NAME#"))?.trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            let location = match bank {
+                Some(bank) if addr >= CARTRIDGE_ROM => {
+                    let offset = bank.checked_mul(PRG_BANK_SIZE).map(|start| start + addr as usize % PRG_BANK_SIZE);
+                    Location::Prg(offset.ok_or_else(|| syntax("Bank is out of range"))?)
+                }
+                _ => Location::Cpu(addr),
+            };
+            self.add(Symbol { name: name.to_string(), location, size });
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // Mesen: `TYPE:OFFSET[-END]:name[:comment]`, the offset counting from the
+    // start of the memory TYPE names. Both Mesen and Mesen 2 type names work
+    pub fn load_mlb(&mut self, text: &str) -> Result<usize, SymbolError> {
+        let mut count = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let syntax = |message: &str| SymbolError::Syntax { line: i + 1, message: message.to_string() };
+
+            let mut fields = line.splitn(4, ':');
+            let (Some(kind), Some(range), Some(name)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(syntax("Expected TYPE:OFFSET:NAME"));
+            };
+            // Entries with only a comment have no name
+            if name.is_empty() {
+                continue;
+            }
+
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse_hex(start), parse_hex(end)),
+                None => (parse_hex(range), parse_hex(range)),
+            };
+            let (Some(start), Some(end)) = (start, end) else {
+                return Err(syntax("Bad offset"));
+            };
+            let size = end.checked_sub(start).ok_or_else(|| syntax("Range ends before it starts"))?;
+            let size = u16::try_from(size).ok().and_then(|size| size.checked_add(1)).ok_or_else(|| syntax("Range is too long"))?;
+
+            let cpu = |base: u16| {
+                start.checked_add(base as usize).and_then(|addr| u16::try_from(addr).ok())
+                    .map(Location::Cpu).ok_or_else(|| syntax("Offset is out of range"))
+            };
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(start),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => cpu(0)?,
+                "S" | "NesSaveRam" | "W" | "NesWorkRam" => cpu(PRG_RAM)?,
+                // CHR and other PPU memory has no CPU address
+                _ => continue,
+            };
+            self.add(Symbol { name: name.to_string(), location, size });
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // ld65's --dbgfile output. Labels in segments written to the output file
+    // get PRG offsets, so each keeps to its bank. Equates are left out, most
+    // are constants rather than addresses
+    pub fn load_dbg(&mut self, text: &str) -> Result<usize, SymbolError> {
+        // Segment id to its start address and offset into the output file
+        let mut segments: HashMap<usize, (usize, Option<usize>)> = HashMap::new();
+        let mut header = 0;
+        let mut symbols = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            let Some((kind, fields)) = line.split_once(char::is_whitespace) else { continue };
+            let syntax = |message: String| SymbolError::Syntax { line: i + 1, message };
+
+            let fields: HashMap<&str, &str> = fields.trim().split(',').filter_map(|field| field.split_once('=')).collect();
+            let number = |key: &str| -> Result<Option<usize>, SymbolError> {
+                fields.get(key).map(|value| parse_dbg_number(value).ok_or_else(|| syntax(format!("Bad {} {}", key, value)))).transpose()
+            };
+            let required = |key: &str| number(key)?.ok_or_else(|| syntax(format!("Missing {}", key)));
+
+            match kind {
+                "seg" => {
+                    let name = fields.get("name").map(|name| name.trim_matches('"'));
+                    // The iNES header comes first in the output file
+                    if name == Some("HEADER") && number("ooffs")? == Some(0) {
+                        header = required("size")?;
+                    }
+                    segments.insert(required("id")?, (required("start")?, number("ooffs")?));
+                }
+                "sym" => {
+                    if fields.get("type") != Some(&"lab") {
+                        continue;
+                    }
+                    // Imported symbols are listed without a value
+                    let Some(value) = number("val")? else { continue };
+                    let name = fields.get("name").ok_or_else(|| syntax("Missing name".to_string()))?.trim_matches('"');
+                    symbols.push((i + 1, name.to_string(), value, number("seg")?, number("size")?.unwrap_or(1)));
+                }
+                _ => {}
+            }
+        }
+
+        // Checked before any is added, so a broken file adds nothing
+        let symbols = symbols.into_iter().map(|(line, name, value, segment, size)| {
+            let addr = value as u16;
+            let location = match segment.and_then(|id| segments.get(&id)) {
+                Some((start, Some(offset))) if addr >= CARTRIDGE_ROM && *offset >= header => {
+                    let offset = value.checked_sub(*start).map(|value| offset - header + value).ok_or_else(|| {
+                        SymbolError::Syntax { line, message: format!("{} is before its segment", name) }
+                    })?;
+                    Location::Prg(offset)
+                }
+                _ => Location::Cpu(addr),
+            };
+            Ok(Symbol { name, location, size: size as u16 })
+        }).collect::<Result<Vec<Symbol>, SymbolError>>()?;
+
+        let count = symbols.len();
+        for symbol in symbols {
+            self.add(symbol);
+        }
+
+        Ok(count)
+    }
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text.trim().trim_start_matches('$'), 16).ok()
+}
+
+fn parse_dbg_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Symbol files next to the ROM: FCEUX's game.nes.ram.nl and game.nes.N.nl,
+// ld65's game.dbg and Mesen's game.mlb
+pub fn find_files(rom_path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = ["dbg", "mlb"].iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .filter(|path| path.is_file())
+        .collect();
+
+    let (Some(dir), Some(rom_name)) = (rom_path.parent(), rom_path.file_name().and_then(|name| name.to_str())) else {
+        return files;
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut nl_files: Vec<PathBuf> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            name.strip_prefix(rom_name).and_then(|name| name.strip_prefix('.')).is_some_and(|name| name.ends_with(".nl"))
+        }))
+        .collect();
+    nl_files.sort();
+
+    files.extend(nl_files);
+    files
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_formats() {
+        let mut symbols = Symbols::default();
+
+        let nl = "$0300/10#buffer#Sprite buffer\n\\continued comment\n$00F0#frame#\n";
+        assert_eq!(symbols.load_nl(nl, None), Ok(2));
+        assert_eq!(symbols.load_nl("$C123#bank_one#\n", Some(1)), Ok(1));
+
+        let mlb = "P:7FFA:nmi_vector\nR:0010:player_x:X position\nW:0002:save_slot\nP:0040::only a comment\n";
+        assert_eq!(symbols.load_mlb(mlb), Ok(3));
+
+        let dbg = "\
+version\tmajor=2,minor=0
+seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0
+seg\tid=1,name=\"CODE\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+seg\tid=2,name=\"BSS\",start=0x000200,size=0x0100,addrsize=absolute,type=rw
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,val=0xC010,seg=1,type=lab
+sym\tid=1,name=\"lives\",addrsize=absolute,scope=0,def=2,val=0x200,seg=2,type=lab
+sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ
+sym\tid=3,name=\"extern\",addrsize=absolute,scope=0,def=4,type=lab";
+        assert_eq!(symbols.load_dbg(dbg), Ok(2));
+
+        assert_eq!(symbols.get("buffer").map(|symbol| (symbol.location, symbol.size)), Some((Location::Cpu(0x0300), 0x10)));
+        assert_eq!(symbols.get("bank_one").unwrap().location, Location::Prg(0x4123));
+        assert_eq!(symbols.get("bank_one").unwrap().bank(), Some(1));
+        assert_eq!(symbols.get("save_slot").unwrap().location, Location::Cpu(0x6002));
+        assert_eq!(symbols.at(Location::Prg(0x0010)).unwrap().name, "reset");
+        assert_eq!(symbols.at(Location::Cpu(0x0200)).unwrap().name, "lives");
+        assert!(symbols.get("PPUCTRL").is_none());
+
+        let outside = "\
+seg\tid=0,name=\"CODE\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+sym\tid=0,name=\"fine\",addrsize=absolute,scope=0,def=1,val=0xC000,seg=0,type=lab
+sym\tid=1,name=\"early\",addrsize=absolute,scope=0,def=2,val=0xBFFF,seg=0,type=lab";
+        assert_eq!(symbols.load_dbg(outside), Err(SymbolError::Syntax { line: 3, message: "early is before its segment".to_string() }));
+        assert!(symbols.get("fine").is_none());
+        assert_eq!(symbols.load_nl("$XYZ#bad#", None), Err(SymbolError::Syntax { line: 1, message: "Bad address".to_string() }));
+
+        let syntax = |message: &str| Err(SymbolError::Syntax { line: 1, message: message.to_string() });
+        assert_eq!(symbols.load_nl("$0300/10000#big#", None), syntax("Array size is out of range"));
+        assert_eq!(symbols.load_nl("$C000#far#", Some(usize::MAX)), syntax("Bank is out of range"));
+        assert_eq!(symbols.load_mlb("R:0010-0000:backwards"), syntax("Range ends before it starts"));
+        assert_eq!(symbols.load_mlb("P:0-FFFFFFFFFFFFFFFF:everything"), syntax("Range is too long"));
+        assert_eq!(symbols.load_mlb("G:FFFFFFFFFFFFFFFF:wraps"), syntax("Offset is out of range"));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::cpu::disasm::decode_at;
+use crate::cpu::trace::trace;
+use crate::debugger::Condition;
+use crate::nes::Nes;
+use crate::symbols::PRG_BANK_SIZE;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TraceFormat {
+    // nestest.log: effective addresses and values, PPU position and cycles
+    #[default]
+    Nestest,
+    // Mesen's default columns: registers, flags as letters, V/H position, frame and cycles
+    Mesen,
+    // FCEUX's trace logger with frame and cycle counts up front
+    Fceux,
+}
+
+// N V U B D I Z C, upper case when set
+fn flag_letters(status: u8) -> String {
+    "NVUBDIZC".chars().enumerate()
+        .map(|(i, c)| if status & (0x80 >> i) != 0 { c } else { c.to_ascii_lowercase() })
+        .collect()
+}
+
+// The line for the instruction at PC, before it runs
+pub fn format_line(nes: &Nes, format: TraceFormat) -> String {
+    let cpu = nes.cpu();
+    let pc = cpu.program_counter;
+    let cycles = nes.bus().cycles();
+    let (scanline, dot) = nes.ppu_position();
+
+    if format == TraceFormat::Nestest {
+        return format!("{} PPU:{:3},{:3} CYC:{}", trace(cpu), scanline, dot, cycles);
+    }
+
+    let instruction = decode_at(&cpu.bus, pc);
+    let bytes: Vec<String> = (0..instruction.size()).map(|i| format!("{:02X}", cpu.bus.peek(pc.wrapping_add(i)))).collect();
+    let disassembly = instruction.format(|addr| cpu.bus.label(addr).map(str::to_string), false);
+    let registers = format!(
+        "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}",
+        cpu.register_a, cpu.register_x, cpu.register_y, cpu.stack_pointer, flag_letters(cpu.status.bits())
+    );
+
+    match format {
+        TraceFormat::Mesen => format!(
+            "{:04X}  {:8}  {:24} {} V:{:<3} H:{:<3} Fr:{} Cyc:{}",
+            pc, bytes.join(" "), disassembly, registers, scanline, dot, nes.frame_count(), cycles
+        ),
+        _ => format!(
+            "f{:<6} c{:<10} {}  ${:04X}:{:8}  {}",
+            nes.frame_count(), cycles, registers, pc, bytes.join(" "), disassembly
+        ),
+    }
+}
+
+// Writes a line per instruction to `out`. Call log before every instruction,
+// like Debugger::should_break. Lines can be limited to a PC range or a PRG
+// bank, and held back until a trigger condition first holds
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    enabled: bool,
+    pc_range: Option<(u16, u16)>,
+    bank: Option<usize>,
+    trigger: Vec<Condition>,
+    triggered: bool,
+    lines: usize,
+}
+
+impl Tracer<BufWriter<File>> {
+    pub fn create(path: &Path, format: TraceFormat) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?), format))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        Tracer {
+            out,
+            format,
+            enabled: true,
+            pc_range: None,
+            bank: None,
+            trigger: vec![],
+            triggered: true,
+            lines: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    // Returns whether tracing is on now
+    pub fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        self.enabled
+    }
+
+    // Inclusive
+    pub fn set_pc_range(&mut self, start: u16, end: u16) {
+        self.pc_range = Some((start.min(end), start.max(end)));
+    }
+
+    // Only instructions in this 16K PRG bank, so nothing from RAM
+    pub fn set_bank(&mut self, bank: usize) {
+        self.bank = Some(bank);
+    }
+
+    // Nothing is written until all `conditions` hold, from then on everything is
+    pub fn set_trigger(&mut self, conditions: Vec<Condition>) {
+        self.triggered = conditions.is_empty();
+        self.trigger = conditions;
+    }
+
+    // Lines written so far
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn log(&mut self, nes: &Nes) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.triggered {
+            self.triggered = self.trigger.iter().all(|condition| condition.holds(nes));
+            if !self.triggered {
+                return Ok(());
+            }
+        }
+
+        let pc = nes.cpu().program_counter;
+        if self.pc_range.is_some_and(|(start, end)| !(start..=end).contains(&pc)) {
+            return Ok(());
+        }
+        if self.bank.is_some_and(|bank| nes.bus().prg_offset(pc).map(|offset| offset / PRG_BANK_SIZE) != Some(bank)) {
+            return Ok(());
+        }
+
+        self.lines += 1;
+        writeln!(self.out, "{}", format_line(nes, self.format))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nestest() -> Nes {
+        let mut nes = crate::nes::test_util::nestest();
+        // nestest's automated mode starts at $C000
+        nes.cpu_mut().program_counter = 0xC000;
+        nes
+    }
+
+    fn run(tracer: &mut Tracer<Vec<u8>>, nes: &mut Nes, instructions: usize) -> Vec<String> {
+        for _ in 0..instructions {
+            tracer.log(nes).unwrap();
+            nes.step();
+        }
+        String::from_utf8(std::mem::take(&mut tracer.out)).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_tracing_doesnt_read_controllers() {
+        let mut nes = nestest();
+        crate::nes::test_util::load_program(&mut nes, "LDA #1\nSTA $4016\nLDA #0\nSTA $4016\nLDA $4016");
+        nes.set_input(0, crate::bus::joypad::JoypadButtons::A);
+
+        let mut tracer = Tracer::new(vec![], TraceFormat::Nestest);
+        let lines = run(&mut tracer, &mut nes, 5);
+        assert!(lines[4].starts_with("060A  AD 16 40  LDA $4016 = 01"));
+        assert_eq!(nes.cpu().register_a, 1);
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut nes = nestest();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Nestest);
+        let lines = run(&mut tracer, &mut nes, 2);
+        // The first lines of nestest.log
+        assert_eq!(lines[0], "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        assert_eq!(lines[1], "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10");
+
+        let mut nes = nestest();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Mesen);
+        assert_eq!(
+            run(&mut tracer, &mut nes, 1)[0],
+            "C000  4C F5 C5  JMP $C5F5                A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Fr:0 Cyc:7"
+        );
+
+        let mut nes = nestest();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Fceux);
+        assert_eq!(
+            run(&mut tracer, &mut nes, 1)[0],
+            "f0      c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5"
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut nes = nestest();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Nestest);
+        tracer.set_pc_range(0xC5F5, 0xC5FF);
+        let lines = run(&mut tracer, &mut nes, 4);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.starts_with("C5F")));
+
+        // $C5F5 LDX #$00, $C5F7 STX $00, $C5F9 STX $10, $C5FB STX $11
+        let mut nes = nestest();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Nestest);
+        tracer.set_trigger(Condition::parse_all("pc == $C5F9", nes.bus()).unwrap());
+        let lines = run(&mut tracer, &mut nes, 5);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("C5F9"));
+
+        tracer.set_enabled(false);
+        assert!(run(&mut tracer, &mut nes, 2).is_empty());
+        assert_eq!(tracer.lines(), 2);
+    }
+}
@@ -0,0 +1,80 @@
+#!/usr/bin/env python3
+"""Converts the NES 2.0 XML database to FamEmu's src/rom/gamedb.txt.
+
+    python3 tools/nes20db_to_gamedb.py nes20db.xml > src/rom/gamedb.txt
+
+nes20db.xml is the NES 2.0 header database maintained on the NESdev forums.
+Each <game> becomes one line; the file name in the comment before it becomes
+the title. Only the Python standard library is needed.
+"""
+
+import os
+import sys
+import xml.etree.ElementTree as ET
+
+HEADER = """\
+# FamEmu game database, looked up by CRC32 of PRG-ROM + CHR-ROM (header and trainer excluded).
+# Generated by tools/nes20db_to_gamedb.py from nes20db.xml{date}, don't edit by hand:
+# entries for ROMs that aren't in nes20db go in gamedb_local.txt.
+#
+# One game per line, fields separated by ';':
+#   CRC32 ; SHA-1 ; mapper ; submapper ; mirroring (H, V, 4, M for mapper controlled) ; battery (0, 1) ; region (NTSC, PAL, World, Dendy) ; title
+"""
+
+# <console region>
+REGIONS = {"0": "NTSC", "1": "PAL", "2": "World", "3": "Dendy"}
+
+
+def title_of(comment):
+    # "Licensed\\Super Mario Bros. (World).nes" -> "Super Mario Bros. (World)"
+    name = comment.strip().replace("\\", "/").rsplit("/", 1)[-1]
+    name = os.path.splitext(name)[0] if name.lower().endswith(".nes") else name
+    # ';' separates fields
+    return name.replace(";", ",")
+
+
+def convert(path):
+    parser = ET.XMLParser(target=ET.TreeBuilder(insert_comments=True))
+    root = ET.parse(path, parser).getroot()
+    lines = []
+    seen = set()
+
+    for game in root.iter("game"):
+        comments = [child.text for child in game if child.tag is ET.Comment]
+        rom = game.find("rom")
+        pcb = game.find("pcb")
+        console = game.find("console")
+        if rom is None or pcb is None:
+            continue
+
+        crc32 = rom.get("crc32", "").upper()
+        if not crc32 or crc32 in seen:
+            continue
+        seen.add(crc32)
+
+        # Mapper-controlled mirroring has no fixed value, FamEmu leaves the header alone then
+        mirroring = pcb.get("mirroring", "M")
+        if mirroring not in ("H", "V", "4"):
+            mirroring = "M"
+        region = REGIONS.get(console.get("region", "0") if console is not None else "0", "NTSC")
+
+        lines.append(";".join([
+            crc32,
+            rom.get("sha1", "").upper(),
+            pcb.get("mapper", "0"),
+            pcb.get("submapper", "0"),
+            mirroring,
+            "1" if pcb.get("battery") == "1" else "0",
+            region,
+            title_of(comments[0]) if comments else "",
+        ]))
+
+    date = root.get("date")
+    sys.stdout.write(HEADER.format(date=" dated " + date if date else ""))
+    sys.stdout.write("".join(line + "\n" for line in sorted(lines)))
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2:
+        sys.exit("usage: nes20db_to_gamedb.py nes20db.xml > src/rom/gamedb.txt")
+    convert(sys.argv[1])